@@ -0,0 +1,271 @@
+//! Offline generation over JSONL records.
+//! Each line is a `{"id": .., "prompt": "..", "max_tokens": ..}` object with the sampling fields
+//! of the server API. Records run on a pool of workers sharing the weights, each worker with its
+//! own K, V pool and prefix cache.
+use std::collections::HashSet;
+use std::fs;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use serde_json::{json, Value};
+
+use crate::kv::{blocks_for, BlockPool, KVFormat, SharedPool};
+use crate::sampling::{opt_usize, SamplingParams};
+use crate::session::{PrefixCache, Session};
+use crate::{Config, LamaExecuter, Ty, Vocab, BOS, EOS};
+
+pub struct Record {
+    /// From the record, or its line number
+    pub id: Value,
+    pub prompt: String,
+    pub params: SamplingParams,
+    /// Up to the end of the context when missing
+    pub max_tokens: usize,
+}
+
+impl Record {
+    /// Parse line `idx` of the input, errors come with the record id
+    pub fn parse(line: &str, idx: usize) -> Result<Self, (Value, String)> {
+        let json: Value =
+            serde_json::from_str(line).map_err(|e| (json!(idx), format!("Bad JSON: {}", e)))?;
+        if !json.is_object() {
+            return Err((json!(idx), "Record must be a JSON object".into()));
+        }
+        let id = match &json["id"] {
+            Value::Null => json!(idx),
+            id => id.clone(),
+        };
+        let fail = |e: String| (id.clone(), e);
+        let prompt = match &json["prompt"] {
+            Value::String(p) => p.clone(),
+            Value::Null => String::new(),
+            _ => return Err(fail("prompt must be a string".into())),
+        };
+        Ok(Self {
+            prompt,
+            params: SamplingParams::from_json(&json).map_err(fail)?,
+            max_tokens: opt_usize(&json, "max_tokens")
+                .map_err(fail)?
+                .unwrap_or(usize::MAX),
+            id,
+        })
+    }
+
+    /// Id of a parsed record, or of one that failed to parse
+    pub fn id_of(record: &Result<Self, (Value, String)>) -> &Value {
+        match record {
+            Ok(record) => &record.id,
+            Err((id, _)) => id,
+        }
+    }
+}
+
+/// Ids of the results in a (partial) output file, dropping a last line cut short
+pub fn completed_ids(path: &str) -> HashSet<String> {
+    let Ok(mut content) = fs::read_to_string(path) else {
+        return HashSet::new();
+    };
+    let complete = content.rfind('\n').map_or(0, |end| end + 1);
+    if complete < content.len() {
+        let file = fs::OpenOptions::new().write(true).open(path).unwrap();
+        file.set_len(complete as u64).unwrap();
+        content.truncate(complete);
+    }
+    content
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|result| !result["id"].is_null())
+        .map(|result| result["id"].to_string())
+        .collect()
+}
+
+/// Records of the `input` lines whose id is not in `done`
+pub fn pending(input: &str, done: &HashSet<String>) -> Vec<Result<Record, (Value, String)>> {
+    input
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(idx, line)| Record::parse(line, idx))
+        .filter(|record| !done.contains(&Record::id_of(record).to_string()))
+        .collect()
+}
+
+/// Generate every record on `workers` threads. `on_result` gets each result as it completes,
+/// a JSON object with `id`, `text`, `tokens`, `finish_reason` (`stop` or `length`),
+/// token counts and timings, or with `id` and `error`.
+pub fn run<W: LamaExecuter<Vec<Ty>> + Sync>(
+    weights: &W,
+    cfg: &Config,
+    vocab: &Vocab,
+    kv_format: KVFormat,
+    records: Vec<Result<Record, (Value, String)>>,
+    workers: usize,
+    mut on_result: impl FnMut(Value),
+) {
+    let queue = Mutex::new(records.into_iter());
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..workers.max(1) {
+            let sender = sender.clone();
+            let queue = &queue;
+            scope.spawn(move || {
+                // a running sequence and a context worth of cached prompt blocks
+                let cached_blocks = blocks_for(cfg.seq_len);
+                let pool = BlockPool::new(cfg, 2 * cached_blocks, kv_format);
+                let pool = Arc::new(Mutex::new(pool));
+                let mut prefix_cache = PrefixCache::new(cached_blocks);
+                loop {
+                    let Some(record) = queue.lock().unwrap().next() else {
+                        break;
+                    };
+                    let result = match record {
+                        Ok(record) => {
+                            generate(weights, cfg, vocab, &pool, &mut prefix_cache, &record)
+                        }
+                        Err((id, e)) => json!({ "id": id, "error": e }),
+                    };
+                    if sender.send(result).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+        receiver.iter().for_each(&mut on_result);
+    });
+}
+
+fn generate<W: LamaExecuter<Vec<Ty>>>(
+    weights: &W,
+    cfg: &Config,
+    vocab: &Vocab,
+    pool: &SharedPool,
+    prefix_cache: &mut PrefixCache,
+    record: &Record,
+) -> Value {
+    let st = Instant::now();
+    let mut prompt = vec![BOS];
+    if !record.prompt.is_empty() {
+        prompt.extend(vocab.encode(&record.prompt));
+    }
+    if prompt.len() >= cfg.seq_len {
+        let e = format!(
+            "Prompt is {} tokens, the context is {}",
+            prompt.len(),
+            cfg.seq_len
+        );
+        return json!({ "id": record.id, "error": e });
+    }
+
+    let mut session = Session::new(cfg, pool);
+    if let Err(e) = prefix_cache.prefill(&mut session, weights, cfg, &prompt) {
+        return json!({ "id": record.id, "error": e });
+    }
+    let prefill_secs = st.elapsed().as_secs_f64();
+    let mut pipeline = record.params.pipeline();
+    let mut tokens = vec![];
+    let finish_reason = loop {
+        if tokens.len() >= record.max_tokens {
+            break "length";
+        }
+        let (history, logits) = session.logits_mut();
+        let next = pipeline.next_token(history, logits);
+        if next == EOS {
+            break "stop";
+        }
+        tokens.push(next);
+        if tokens.len() >= record.max_tokens || session.pos() >= cfg.seq_len {
+            break "length";
+        }
+        if let Err(e) = session.feed(weights, cfg, next) {
+            return json!({ "id": record.id, "error": e });
+        }
+    };
+
+    let bytes = tokens
+        .iter()
+        .flat_map(|&t| vocab.token_bytes(t))
+        .copied()
+        .collect::<Vec<_>>();
+    json!({
+        "id": record.id,
+        "text": String::from_utf8_lossy(&bytes),
+        "tokens": tokens,
+        "finish_reason": finish_reason,
+        "prompt_tokens": prompt.len(),
+        "completion_tokens": tokens.len(),
+        "prefill_secs": prefill_secs,
+        "total_secs": st.elapsed().as_secs_f64(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::tests::random_model;
+    use std::io::Write;
+
+    const TOKENIZER: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../tokenizer.bin");
+
+    #[test]
+    fn resume_skips_completed() {
+        let cfg = Config {
+            dim: 64,
+            hidden_dim: 172,
+            n_layers: 2,
+            n_heads: 4,
+            n_kv_heads: 4,
+            vocab_size: 32000,
+            seq_len: 32,
+            shared_weights: false,
+        };
+        let weights = random_model(&cfg);
+        let vocab = Vocab::from_file(cfg.vocab_size, TOKENIZER);
+        let input = [
+            r#"{"id": "a", "prompt": "Hi", "max_tokens": 2}"#,
+            r#"{"prompt": "Yo", "max_tokens": 2}"#,
+            "",
+            r#"{"id": "c", "prompt": "Hey", "max_tokens": 2}"#,
+            r#"{"id": "d", "prompt": "Hm", "max_tokens": 2}"#,
+        ]
+        .join("\n");
+        // "a" and line 1 done, "c" cut short by an interruption
+        let path = std::env::temp_dir().join(format!("batch-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "{\"id\":\"a\"}\n{\"id\":1}\n{\"id\":\"c\",\"te").unwrap();
+
+        let ids = |path: &str| {
+            let mut ids = fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .map(|l| serde_json::from_str::<Value>(l).unwrap()["id"].to_string())
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+        let done = completed_ids(path);
+        assert_eq!(ids(path), ["\"a\"", "1"]);
+        let records = pending(&input, &done);
+        let pending_ids = records.iter().map(|r| Record::id_of(r).clone());
+        assert_eq!(pending_ids.collect::<Vec<_>>(), [json!("c"), json!("d")]);
+
+        let mut output = fs::OpenOptions::new().append(true).open(path).unwrap();
+        run(
+            &weights,
+            &cfg,
+            &vocab,
+            KVFormat::F32,
+            records,
+            2,
+            |result| {
+                assert!(result["error"].is_null(), "{}", result);
+                writeln!(output, "{}", result).unwrap();
+            },
+        );
+        assert_eq!(ids(path), ["\"a\"", "\"c\"", "\"d\"", "1"]);
+        assert!(pending(&input, &completed_ids(path)).is_empty());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,236 @@
+//! Beam search decoding.
+//! Every beam is a [`Session`], children are forks of their parent so they share its K, V blocks
+//! until they write to them.
+use crate::sampling::{log_softmax, top_logprobs};
+use crate::session::{Session, POOL_EXHAUSTED};
+use crate::{Config, LamaExecuter, Ty, EOS};
+
+#[derive(Debug, Clone, Copy)]
+pub struct BeamParams {
+    /// Number of beams kept at each step
+    pub width: usize,
+    /// Finished beams are ranked by `logprob / len^length_penalty`,
+    /// above 1.0 favors longer outputs, below 1.0 shorter ones
+    pub length_penalty: Ty,
+    /// Stop as soon as `width` beams are finished, instead of once no running beam can beat them
+    pub early_stopping: bool,
+    /// Max number of generated tokens
+    pub max_new_tokens: usize,
+}
+
+impl Default for BeamParams {
+    fn default() -> Self {
+        Self {
+            width: 4,
+            length_penalty: 1 as Ty,
+            early_stopping: false,
+            max_new_tokens: usize::MAX,
+        }
+    }
+}
+
+/// A finished beam
+#[derive(Debug, Clone)]
+pub struct Hypothesis {
+    /// Generated tokens, without the prompt and EOS
+    pub tokens: Vec<usize>,
+    /// Sum of the token log probabilities (including EOS if generated)
+    pub logprob: Ty,
+    /// Length penalized score the hypotheses are ranked by
+    pub score: Ty,
+}
+
+struct Beam {
+    session: Session,
+    logprob: Ty,
+}
+
+/// Beam search from `session`, which holds the prompt (and its last logits).
+/// Returns up to `width` hypotheses, best first.
+pub fn beam_search<W: LamaExecuter<Vec<Ty>>>(
+    session: Session,
+    weights: &W,
+    cfg: &Config,
+    params: &BeamParams,
+) -> Vec<Hypothesis> {
+    assert!(params.width > 0, "Beam width must be at least 1");
+    let prompt_len = session.pos();
+    let max_len = cfg
+        .seq_len
+        .min(prompt_len.saturating_add(params.max_new_tokens));
+    let score = |logprob: Ty, len: usize| logprob / (len.max(1) as Ty).powf(params.length_penalty);
+
+    let mut beams = vec![Beam {
+        session,
+        logprob: 0 as Ty,
+    }];
+    let mut finished: Vec<Hypothesis> = vec![];
+    loop {
+        // candidates of all beams: (score so far, beam, token), the best `2 * width` of each
+        // beam are enough to fill `width` running beams even if `width` of them are EOS
+        let mut candidates = vec![];
+        for (idx, beam) in beams.iter().enumerate() {
+            let logprobs = log_softmax(&beam.session.state.logits);
+            candidates.extend(
+                top_logprobs(&logprobs, 2 * params.width)
+                    .into_iter()
+                    .map(|(t, logprob)| (beam.logprob + logprob, idx, t)),
+            );
+        }
+        candidates.sort_unstable_by(|a, b| b.0.total_cmp(&a.0));
+
+        let len = beams[0].session.pos() - prompt_len;
+        let mut selected = vec![];
+        for (logprob, idx, token) in candidates {
+            if token == EOS {
+                let tokens = beams[idx].session.tokens()[prompt_len..].to_vec();
+                finished.push(Hypothesis {
+                    score: score(logprob, len + 1),
+                    tokens,
+                    logprob,
+                });
+            } else {
+                selected.push((logprob, idx, token));
+            }
+            if selected.len() == params.width {
+                break;
+            }
+        }
+        finished.sort_unstable_by(|a, b| b.score.total_cmp(&a.score));
+        finished.truncate(params.width);
+
+        let done = finished.len() == params.width
+            && (params.early_stopping
+                // running beams only lose logprob, with a length penalty > 0 their best score
+                // is at most the current one at the longest possible length
+                || selected.first().is_none_or(|&(logprob, _, _)| {
+                    let best_len = if params.length_penalty > 0 as Ty {
+                        max_len - prompt_len
+                    } else {
+                        len + 1
+                    };
+                    score(logprob, best_len) <= finished[params.width - 1].score
+                }));
+        if done || selected.is_empty() {
+            break;
+        }
+        if prompt_len + len + 1 >= max_len {
+            // out of room, the running beams end here
+            for &(logprob, idx, token) in &selected {
+                let mut tokens = beams[idx].session.tokens()[prompt_len..].to_vec();
+                tokens.push(token);
+                finished.push(Hypothesis {
+                    score: score(logprob, tokens.len()),
+                    tokens,
+                    logprob,
+                });
+            }
+            finished.sort_unstable_by(|a, b| b.score.total_cmp(&a.score));
+            finished.truncate(params.width);
+            break;
+        }
+
+        // fork the parents, the last child of each takes the parent session itself
+        let mut children_left = vec![0; beams.len()];
+        selected
+            .iter()
+            .for_each(|&(_, idx, _)| children_left[idx] += 1);
+        let mut parents = beams.into_iter().map(Some).collect::<Vec<_>>();
+        let mut next = vec![];
+        for &(logprob, idx, _) in &selected {
+            children_left[idx] -= 1;
+            let session = match children_left[idx] {
+                0 => parents[idx].take().unwrap().session,
+                _ => parents[idx].as_ref().unwrap().session.fork(),
+            };
+            next.push(Beam { session, logprob });
+        }
+        drop(parents);
+        for (beam, &(_, _, token)) in next.iter_mut().zip(&selected) {
+            beam.session
+                .feed(weights, cfg, token)
+                .expect(POOL_EXHAUSTED);
+        }
+        beams = next;
+    }
+    finished
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::tests::random_model;
+    use crate::kv::{blocks_for, BlockPool, KVFormat, SharedPool};
+    use crate::sampling::argmax;
+    use std::sync::{Arc, Mutex};
+
+    const CFG: Config = Config {
+        dim: 64,
+        hidden_dim: 172,
+        n_layers: 2,
+        n_heads: 4,
+        n_kv_heads: 4,
+        vocab_size: 256,
+        seq_len: 64,
+        shared_weights: false,
+    };
+
+    fn prompt_session<W: LamaExecuter<Vec<Ty>>>(weights: &W, pool: &SharedPool) -> Session {
+        let mut session = Session::new(&CFG, pool);
+        for token in [
+            1, 72, 101, 108, 108, 111, 44, 32, 119, 111, 114, 108, 100, 33, 10, 84, 104,
+        ] {
+            session.feed(weights, &CFG, token).unwrap();
+        }
+        session
+    }
+
+    #[test]
+    fn width_one_is_greedy() {
+        let weights = random_model(&CFG);
+        let pool = Arc::new(Mutex::new(BlockPool::new(&CFG, 8, KVFormat::F32)));
+        let params = BeamParams {
+            width: 1,
+            early_stopping: true,
+            max_new_tokens: 20,
+            ..Default::default()
+        };
+        let beam = beam_search(prompt_session(&weights, &pool), &weights, &CFG, &params);
+
+        let mut session = prompt_session(&weights, &pool);
+        let mut greedy = vec![];
+        for _ in 0..params.max_new_tokens {
+            let next = argmax(&session.state.logits);
+            if next == EOS {
+                break;
+            }
+            greedy.push(next);
+            session.feed(&weights, &CFG, next).unwrap();
+        }
+        assert_eq!(beam.len(), 1);
+        assert_eq!(beam[0].tokens, greedy);
+    }
+
+    #[test]
+    fn blocks_are_released() {
+        let weights = random_model(&CFG);
+        let pool = Arc::new(Mutex::new(BlockPool::new(
+            &CFG,
+            8 * blocks_for(CFG.seq_len),
+            KVFormat::F32,
+        )));
+        let session = prompt_session(&weights, &pool);
+        // a fork keeps the prompt blocks shared during the search
+        let prompt = session.fork();
+        let baseline = pool.lock().unwrap().used();
+        let params = BeamParams {
+            width: 4,
+            ..Default::default()
+        };
+        let hypotheses = beam_search(session, &weights, &CFG, &params);
+        assert_eq!(hypotheses.len(), 4);
+        assert_eq!(pool.lock().unwrap().used(), baseline);
+        drop(prompt);
+        assert_eq!(pool.lock().unwrap().used(), 0);
+    }
+}
@@ -0,0 +1,164 @@
+//! Inference speed benchmark.
+//! Each iteration prefills a fixed prompt in a fresh session and greedily decodes a fixed number
+//! of tokens (EOS doesn't stop it), so runs are comparable across commits and machines.
+use std::time::Instant;
+
+use serde_json::{json, Value};
+
+use crate::kv::SharedPool;
+use crate::profile;
+use crate::sampling::argmax;
+use crate::session::{Session, POOL_EXHAUSTED};
+use crate::{Config, LamaExecuter, Ty};
+
+#[derive(Debug, Clone, Copy)]
+pub struct BenchParams {
+    /// Generated tokens per iteration, the first one comes from the prefill logits
+    pub gen_len: usize,
+    /// Untimed iterations, to warm up caches and the thread pool
+    pub warmup: usize,
+    pub iterations: usize,
+}
+
+/// Timings of one iteration, in seconds
+#[derive(Debug, Clone, Default)]
+pub struct Iteration {
+    pub prefill_secs: f64,
+    /// Prefill and sampling the first token
+    pub ttft_secs: f64,
+    /// Each token after the first one
+    pub token_secs: Vec<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    pub prompt_len: usize,
+    pub params: BenchParams,
+    pub iterations: Vec<Iteration>,
+}
+
+impl BenchReport {
+    pub fn prefill_tok_s(&self) -> f64 {
+        let secs = self
+            .iterations
+            .iter()
+            .map(|it| it.prefill_secs)
+            .sum::<f64>();
+        (self.prompt_len * self.iterations.len()) as f64 / secs
+    }
+
+    pub fn decode_tok_s(&self) -> f64 {
+        let latencies = self.token_latencies();
+        latencies.len() as f64 / latencies.iter().sum::<f64>()
+    }
+
+    /// Median time to first token
+    pub fn ttft_secs(&self) -> f64 {
+        let ttft = self.iterations.iter().map(|it| it.ttft_secs).collect();
+        percentile(ttft, 50.)
+    }
+
+    /// Per token decode latency percentile (0 to 100), over all iterations
+    pub fn token_latency(&self, p: f64) -> f64 {
+        percentile(self.token_latencies(), p)
+    }
+
+    fn token_latencies(&self) -> Vec<f64> {
+        self.iterations
+            .iter()
+            .flat_map(|it| it.token_secs.iter().copied())
+            .collect()
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "prompt_len": self.prompt_len,
+            "gen_len": self.params.gen_len,
+            "warmup": self.params.warmup,
+            "iterations": self.iterations.len(),
+            "prefill_tok_s": self.prefill_tok_s(),
+            "decode_tok_s": self.decode_tok_s(),
+            "ttft_secs": self.ttft_secs(),
+            "token_latency_p50_secs": self.token_latency(50.),
+            "token_latency_p95_secs": self.token_latency(95.),
+            "prefill_secs": self.iterations.iter().map(|it| it.prefill_secs).collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Nearest rank percentile, NaN without samples
+fn percentile(mut samples: Vec<f64>, p: f64) -> f64 {
+    if samples.is_empty() {
+        return f64::NAN;
+    }
+    samples.sort_by(f64::total_cmp);
+    let rank = (p / 100. * samples.len() as f64).ceil() as usize;
+    samples[rank.clamp(1, samples.len()) - 1]
+}
+
+/// Benchmark `prompt` (BOS included) followed by `params.gen_len` generated tokens.
+/// `on_iteration` sees each timed iteration as it completes. A running [`profile`] only keeps
+/// the timed iterations.
+pub fn run<W: LamaExecuter<Vec<Ty>>>(
+    weights: &W,
+    cfg: &Config,
+    pool: &SharedPool,
+    prompt: &[usize],
+    params: BenchParams,
+    mut on_iteration: impl FnMut(usize, &Iteration),
+) -> BenchReport {
+    assert!(!prompt.is_empty(), "Prompt must hold at least BOS");
+    assert!(params.gen_len > 0, "Must generate at least one token");
+    assert!(
+        prompt.len() + params.gen_len <= cfg.seq_len + 1,
+        "Prompt and generated tokens don't fit in the context of {}",
+        cfg.seq_len
+    );
+
+    let mut iterations = vec![];
+    for idx in 0..params.warmup + params.iterations {
+        if idx == params.warmup {
+            profile::reset();
+        }
+        let iteration = iterate(weights, cfg, pool, prompt, params.gen_len);
+        if idx >= params.warmup {
+            on_iteration(idx - params.warmup, &iteration);
+            iterations.push(iteration);
+        }
+    }
+    BenchReport {
+        prompt_len: prompt.len(),
+        params,
+        iterations,
+    }
+}
+
+fn iterate<W: LamaExecuter<Vec<Ty>>>(
+    weights: &W,
+    cfg: &Config,
+    pool: &SharedPool,
+    prompt: &[usize],
+    gen_len: usize,
+) -> Iteration {
+    let mut session = Session::new(cfg, pool);
+    let st = Instant::now();
+    for &token in prompt {
+        session.feed(weights, cfg, token).expect(POOL_EXHAUSTED);
+    }
+    let prefill_secs = st.elapsed().as_secs_f64();
+    let mut next = argmax(&session.state.logits);
+    let ttft_secs = st.elapsed().as_secs_f64();
+
+    let mut token_secs = Vec::with_capacity(gen_len - 1);
+    for _ in 1..gen_len {
+        let st = Instant::now();
+        session.feed(weights, cfg, next).expect(POOL_EXHAUSTED);
+        next = argmax(&session.state.logits);
+        token_secs.push(st.elapsed().as_secs_f64());
+    }
+    Iteration {
+        prefill_secs,
+        ttft_secs,
+        token_secs,
+    }
+}
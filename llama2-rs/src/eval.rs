@@ -0,0 +1,226 @@
+//! Model quality evaluation.
+use crate::kv::SharedPool;
+use crate::sampling::log_softmax;
+use crate::session::{Session, POOL_EXHAUSTED};
+use crate::{Config, LamaExecuter, Ty, BOS};
+
+/// Negative log likelihood of a text
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Perplexity {
+    /// Summed over the scored tokens
+    pub nll: f64,
+    /// Number of scored tokens
+    pub tokens: usize,
+    /// Number of tokens run through the model (windows overlap with a stride below the window)
+    pub processed: usize,
+}
+
+impl Perplexity {
+    pub fn mean_nll(&self) -> f64 {
+        self.nll / self.tokens.max(1) as f64
+    }
+
+    pub fn perplexity(&self) -> f64 {
+        self.mean_nll().exp()
+    }
+}
+
+/// A window of [`perplexity`]: tokens `begin..end` run after BOS, the ones from `scored_from`
+/// on are scored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    pub begin: usize,
+    pub scored_from: usize,
+    pub end: usize,
+}
+
+/// Windows of `window` positions (BOS included) starting every `stride` tokens over `len` tokens.
+/// Each token is scored once, in the first window that predicts it, so a stride below the window
+/// gives later tokens more context. Fails if the stride would leave tokens unscored.
+pub fn windows(len: usize, window: usize, stride: usize) -> Result<Vec<Window>, String> {
+    if window < 2 {
+        return Err("Window must hold BOS and at least one token".into());
+    }
+    let span = window - 1;
+    if !(1..=span).contains(&stride) {
+        return Err(format!(
+            "Stride {} must be between 1 and the window minus BOS ({}), or tokens go unscored",
+            stride, span
+        ));
+    }
+    let mut out = vec![];
+    let (mut begin, mut scored_from) = (0, 0);
+    while scored_from < len {
+        let end = (begin + span).min(len);
+        out.push(Window {
+            begin,
+            scored_from,
+            end,
+        });
+        scored_from = end;
+        begin += stride;
+    }
+    Ok(out)
+}
+
+/// Perplexity of `tokens` (without BOS) over the [`windows`] of `window` positions (at most
+/// `seq_len`) and `stride`. `on_window` sees the running result after each window.
+pub fn perplexity<W: LamaExecuter<Vec<Ty>>>(
+    weights: &W,
+    cfg: &Config,
+    pool: &SharedPool,
+    tokens: &[usize],
+    window: usize,
+    stride: usize,
+    mut on_window: impl FnMut(&Perplexity),
+) -> Result<Perplexity, String> {
+    let windows = windows(tokens.len(), window.min(cfg.seq_len), stride)?;
+    let mut result = Perplexity::default();
+    for Window {
+        begin,
+        scored_from,
+        end,
+    } in windows
+    {
+        let mut session = Session::new(cfg, pool);
+        session.feed(weights, cfg, BOS)?;
+        result.processed += 1;
+        for (idx, &token) in tokens.iter().enumerate().take(end).skip(begin) {
+            if idx >= scored_from {
+                let logprobs = log_softmax(&session.state.logits);
+                result.nll -= logprobs[token] as f64;
+                result.tokens += 1;
+            }
+            // the last token logits predict nothing we score
+            if idx + 1 < end {
+                session.feed(weights, cfg, token)?;
+                result.processed += 1;
+            }
+        }
+        on_window(&result);
+    }
+    Ok(result)
+}
+
+/// Log likelihood of a continuation given its context
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ContinuationScore {
+    /// Summed over the continuation tokens
+    pub logprob: f64,
+    pub tokens: usize,
+}
+
+impl ContinuationScore {
+    /// Mean per token, so longer continuations are not penalized for being longer
+    pub fn normalized(&self) -> f64 {
+        self.logprob / self.tokens.max(1) as f64
+    }
+}
+
+/// `context` (BOS included) left truncated so that it fits in `seq_len` with the longest of
+/// `continuations`, BOS kept. `None` if a continuation doesn't fit even after BOS alone.
+pub fn fit_context(
+    context: &[usize],
+    continuations: &[Vec<usize>],
+    seq_len: usize,
+) -> Option<Vec<usize>> {
+    assert!(!context.is_empty(), "Context must hold at least BOS");
+    let longest = continuations.iter().map(Vec::len).max().unwrap_or(0);
+    if 1 + longest > seq_len {
+        return None;
+    }
+    let excess = (context.len() + longest).saturating_sub(seq_len);
+    let mut fitted = vec![context[0]];
+    fitted.extend_from_slice(&context[1 + excess..]);
+    Some(fitted)
+}
+
+/// Score each of `continuations` after `context` (BOS included).
+/// The context runs through the model once, every continuation continues a fork of it.
+pub fn score_continuations<W: LamaExecuter<Vec<Ty>>>(
+    weights: &W,
+    cfg: &Config,
+    pool: &SharedPool,
+    context: &[usize],
+    continuations: &[Vec<usize>],
+) -> Vec<ContinuationScore> {
+    assert!(!context.is_empty(), "Context must hold at least BOS");
+    let longest = continuations.iter().map(Vec::len).max().unwrap_or(0);
+    assert!(
+        context.len() + longest <= cfg.seq_len,
+        "Context and continuation don't fit in seq_len"
+    );
+
+    let mut session = Session::new(cfg, pool);
+    context
+        .iter()
+        .for_each(|&token| session.feed(weights, cfg, token).expect(POOL_EXHAUSTED));
+
+    continuations
+        .iter()
+        .map(|continuation| {
+            let mut fork = session.fork();
+            let mut score = ContinuationScore::default();
+            for (idx, &token) in continuation.iter().enumerate() {
+                score.logprob += log_softmax(&fork.state.logits)[token] as f64;
+                score.tokens += 1;
+                if idx + 1 < continuation.len() {
+                    fork.feed(weights, cfg, token).expect(POOL_EXHAUSTED);
+                }
+            }
+            score
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Each token scored exactly once, in order
+    fn assert_covers(windows: &[Window], len: usize) {
+        let scored = windows
+            .iter()
+            .flat_map(|w| w.scored_from..w.end)
+            .collect::<Vec<_>>();
+        assert_eq!(scored, (0..len).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn context_fits_with_continuations() {
+        let context = [BOS, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19];
+        let continuations = [vec![1; 3], vec![1; 5]];
+        assert_eq!(
+            fit_context(&context, &continuations, 10).unwrap(),
+            [BOS, 16, 17, 18, 19]
+        );
+        assert_eq!(fit_context(&context, &continuations, 16).unwrap(), context);
+        // only BOS is left
+        assert_eq!(fit_context(&context, &[vec![1; 9]], 10).unwrap(), [BOS]);
+        assert_eq!(fit_context(&context, &[vec![1; 10]], 10), None);
+    }
+
+    #[test]
+    fn overlapping_windows() {
+        let windows = windows(10, 5, 2).unwrap();
+        let bounds = windows
+            .iter()
+            .map(|w| (w.begin, w.scored_from, w.end))
+            .collect::<Vec<_>>();
+        assert_eq!(bounds, [(0, 0, 4), (2, 4, 6), (4, 6, 8), (6, 8, 10)]);
+        assert_covers(&windows, 10);
+    }
+
+    #[test]
+    fn window_strides() {
+        for (len, window, stride) in [(10, 5, 4), (9, 5, 4), (10, 5, 1), (3, 8, 7), (0, 4, 2)] {
+            let windows = windows(len, window, stride).unwrap();
+            assert_covers(&windows, len);
+            assert!(windows.iter().all(|w| w.end - w.begin < window));
+        }
+        // a window of `window` positions scores `window - 1` tokens after BOS
+        assert!(windows(10, 5, 5).is_err());
+        assert!(windows(10, 5, 0).is_err());
+        assert!(windows(10, 1, 1).is_err());
+    }
+}
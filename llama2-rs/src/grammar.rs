@@ -0,0 +1,647 @@
+//! Grammar constrained decoding.
+//! Grammars use the GBNF flavour of BNF:
+//!
+//! ```text
+//! # comments run to the end of the line
+//! root   ::= answer ("," ws answer)*
+//! answer ::= "yes" | "no" | [0-9]+
+//! ws     ::= [ \t\n]?
+//! ```
+//!
+//! Rules are sequences of string literals, char classes (`[a-z]`, `[^"]`), `.` (any char),
+//! rule references and parenthesized groups, with `*`, `+` and `?` repetitions and `|` between
+//! alternatives. Generation starts at `root`. Left recursive rules are not supported.
+//!
+//! The matcher keeps the set of possible parser stacks (positions in the grammar still to match).
+//! A token is allowed if feeding its chars leaves at least one stack alive.
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::sampling::LogitsProcessor;
+use crate::{Ty, Vocab, BYTE_OFFSET, EOS};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Element {
+    /// Match one char in (or with `negated`, not in) the inclusive ranges
+    Char {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Rule(usize),
+}
+
+impl Element {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Element::Char { ranges, negated } => {
+                ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != *negated
+            }
+            Element::Rule(_) => false,
+        }
+    }
+}
+
+/// Rule, alternative, element index
+type Pos = (usize, usize, usize);
+/// Innermost position last
+type Stack = Vec<Pos>;
+
+/// Deepest rule nesting we follow, guards against left recursion
+const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct Grammar {
+    /// rule -> alternatives -> sequence of elements
+    rules: Vec<Vec<Vec<Element>>>,
+    names: Vec<String>,
+    root: usize,
+}
+
+impl Grammar {
+    pub fn parse(src: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            src: src.chars().collect(),
+            at: 0,
+            grammar: Grammar {
+                rules: vec![],
+                names: vec![],
+                root: 0,
+            },
+            defined: vec![],
+        };
+        parser.parse_rules()?;
+        let Parser {
+            grammar, defined, ..
+        } = parser;
+        if let Some(idx) = (0..grammar.rules.len()).find(|&idx| !defined[idx]) {
+            return Err(format!(
+                "Rule {} is used but never defined",
+                grammar.names[idx]
+            ));
+        }
+        let root = grammar
+            .names
+            .iter()
+            .position(|n| n == "root")
+            .ok_or("Grammar has no root rule")?;
+        Ok(Grammar { root, ..grammar })
+    }
+
+    /// Stacks before any char is matched
+    pub fn initial_stacks(&self) -> Vec<Stack> {
+        let (mut seen, mut out) = (HashSet::new(), HashSet::new());
+        for alt in 0..self.rules[self.root].len() {
+            self.expand(vec![(self.root, alt, 0)], &mut seen, &mut out);
+        }
+        Self::sorted(out)
+    }
+
+    /// Sets of stacks in a canonical order, so equal sets compare equal
+    fn sorted(stacks: HashSet<Stack>) -> Vec<Stack> {
+        let mut stacks = stacks.into_iter().collect::<Vec<_>>();
+        stacks.sort_unstable();
+        stacks
+    }
+
+    /// Resolve rule references until every stack waits on a char (or is done, i.e. empty).
+    /// `seen` holds the stacks expanded so far, repeating something that matches the empty
+    /// string comes back to one of them instead of looping forever.
+    fn expand(&self, mut stack: Stack, seen: &mut HashSet<Stack>, out: &mut HashSet<Stack>) {
+        if stack.len() > MAX_DEPTH || !seen.insert(stack.clone()) {
+            return;
+        }
+        let Some(&(rule, alt, idx)) = stack.last() else {
+            out.insert(stack);
+            return;
+        };
+        let seq = &self.rules[rule][alt];
+        if idx == seq.len() {
+            stack.pop();
+            return self.expand(stack, seen, out);
+        }
+        match &seq[idx] {
+            Element::Char { .. } => {
+                out.insert(stack);
+            }
+            &Element::Rule(sub) => {
+                stack.last_mut().unwrap().2 += 1;
+                if idx + 1 == seq.len() {
+                    // tail call, drop the finished frame so repetitions don't grow the stack
+                    stack.pop();
+                }
+                for sub_alt in 0..self.rules[sub].len() {
+                    let mut s = stack.clone();
+                    s.push((sub, sub_alt, 0));
+                    self.expand(s, seen, out);
+                }
+            }
+        }
+    }
+
+    /// Stacks after matching `c`, empty if `c` is not allowed
+    pub fn accept(&self, stacks: &[Stack], c: char) -> Vec<Stack> {
+        let (mut seen, mut out) = (HashSet::new(), HashSet::new());
+        for stack in stacks {
+            let Some(&(rule, alt, idx)) = stack.last() else {
+                continue;
+            };
+            if self.rules[rule][alt][idx].matches(c) {
+                let mut s = stack.clone();
+                s.last_mut().unwrap().2 += 1;
+                self.expand(s, &mut seen, &mut out);
+            }
+        }
+        Self::sorted(out)
+    }
+
+    /// The text matched so far is a complete sentence
+    pub fn is_complete(stacks: &[Stack]) -> bool {
+        stacks.iter().any(|s| s.is_empty())
+    }
+}
+
+struct Parser {
+    src: Vec<char>,
+    at: usize,
+    grammar: Grammar,
+    /// Rule has a definition (and not just references)
+    defined: Vec<bool>,
+}
+
+impl Parser {
+    fn err<T>(&self, msg: &str) -> Result<T, String> {
+        let line = self.src[..self.at.min(self.src.len())]
+            .iter()
+            .filter(|&&c| c == '\n')
+            .count();
+        Err(format!("Grammar error at line {}: {}", line + 1, msg))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src.get(self.at).copied()
+    }
+
+    fn next(&mut self) -> Result<char, String> {
+        let c = self.peek();
+        self.at += 1;
+        c.map_or_else(|| self.err("Unexpected end of grammar"), Ok)
+    }
+
+    /// Skip whitespace (including new lines) and comments
+    fn skip_space(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.at += 1;
+                }
+            } else if c.is_whitespace() {
+                self.at += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn is_name_char(c: char) -> bool {
+        c.is_ascii_alphanumeric() || c == '-' || c == '_'
+    }
+
+    fn parse_name(&mut self) -> String {
+        let st = self.at;
+        while self.peek().is_some_and(Self::is_name_char) {
+            self.at += 1;
+        }
+        self.src[st..self.at].iter().collect()
+    }
+
+    /// The next thing is `name ::=`
+    fn at_rule_start(&mut self) -> bool {
+        let st = self.at;
+        let name = self.parse_name();
+        self.skip_space();
+        let is_def = !name.is_empty() && self.src[self.at..].starts_with(&[':', ':', '=']);
+        self.at = st;
+        is_def
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        match self.grammar.names.iter().position(|n| n == name) {
+            Some(idx) => idx,
+            None => self.new_rule(name.to_string(), vec![]),
+        }
+    }
+
+    fn new_rule(&mut self, name: String, alts: Vec<Vec<Element>>) -> usize {
+        self.grammar.names.push(name);
+        self.grammar.rules.push(alts);
+        self.defined.push(false);
+        self.grammar.rules.len() - 1
+    }
+
+    /// Helper rule for groups and repetitions
+    fn anon_rule(&mut self, alts: Vec<Vec<Element>>) -> usize {
+        let name = format!("_anon{}", self.grammar.rules.len());
+        let idx = self.new_rule(name, alts);
+        self.defined[idx] = true;
+        idx
+    }
+
+    fn parse_rules(&mut self) -> Result<(), String> {
+        self.skip_space();
+        while self.peek().is_some() {
+            let name = self.parse_name();
+            if name.is_empty() {
+                return self.err("Expected a rule name");
+            }
+            self.skip_space();
+            if !self.src[self.at..].starts_with(&[':', ':', '=']) {
+                return self.err("Expected ::=");
+            }
+            self.at += 3;
+            let alts = self.parse_alternatives()?;
+            let idx = self.rule_id(&name);
+            if self.defined[idx] {
+                return self.err(&format!("Rule {} is defined twice", name));
+            }
+            self.grammar.rules[idx] = alts;
+            self.defined[idx] = true;
+            self.skip_space();
+        }
+        Ok(())
+    }
+
+    fn parse_alternatives(&mut self) -> Result<Vec<Vec<Element>>, String> {
+        let mut alts = vec![self.parse_sequence()?];
+        while self.peek() == Some('|') {
+            self.at += 1;
+            alts.push(self.parse_sequence()?);
+        }
+        Ok(alts)
+    }
+
+    fn parse_sequence(&mut self) -> Result<Vec<Element>, String> {
+        let mut seq = vec![];
+        loop {
+            self.skip_space();
+            let symbol = match self.peek() {
+                None | Some('|') | Some(')') => break,
+                Some('"') => {
+                    self.at += 1;
+                    let mut lit = vec![];
+                    loop {
+                        match self.next()? {
+                            '"' => break,
+                            '\\' => lit.push(self.parse_escape()?),
+                            c => lit.push(c),
+                        }
+                    }
+                    lit.into_iter()
+                        .map(|c| Element::Char {
+                            ranges: vec![(c, c)],
+                            negated: false,
+                        })
+                        .collect()
+                }
+                Some('[') => {
+                    self.at += 1;
+                    let negated = self.peek() == Some('^');
+                    if negated {
+                        self.at += 1;
+                    }
+                    let mut ranges = vec![];
+                    loop {
+                        let lo = match self.next()? {
+                            ']' => break,
+                            '\\' => self.parse_escape()?,
+                            c => c,
+                        };
+                        let hi = if self.peek() == Some('-')
+                            && self.src.get(self.at + 1).is_some_and(|&c| c != ']')
+                        {
+                            self.at += 1;
+                            match self.next()? {
+                                '\\' => self.parse_escape()?,
+                                c => c,
+                            }
+                        } else {
+                            lo
+                        };
+                        ranges.push((lo, hi));
+                    }
+                    vec![Element::Char { ranges, negated }]
+                }
+                Some('.') => {
+                    self.at += 1;
+                    vec![Element::Char {
+                        ranges: vec![],
+                        negated: true,
+                    }]
+                }
+                Some('(') => {
+                    self.at += 1;
+                    let alts = self.parse_alternatives()?;
+                    if self.next()? != ')' {
+                        return self.err("Expected )");
+                    }
+                    vec![Element::Rule(self.anon_rule(alts))]
+                }
+                Some(c) if Self::is_name_char(c) => {
+                    if self.at_rule_start() {
+                        break;
+                    }
+                    let name = self.parse_name();
+                    vec![Element::Rule(self.rule_id(&name))]
+                }
+                Some(c) => return self.err(&format!("Unexpected {:?}", c)),
+            };
+
+            let symbol = match self.peek() {
+                Some('*') => {
+                    // R ::= symbol R | <empty>
+                    self.at += 1;
+                    let rule = self.anon_rule(vec![]);
+                    let repeat = [symbol, vec![Element::Rule(rule)]].concat();
+                    self.grammar.rules[rule] = vec![repeat, vec![]];
+                    vec![Element::Rule(rule)]
+                }
+                Some('+') => {
+                    // R ::= symbol R | symbol
+                    self.at += 1;
+                    let rule = self.anon_rule(vec![]);
+                    let repeat = [symbol.clone(), vec![Element::Rule(rule)]].concat();
+                    self.grammar.rules[rule] = vec![repeat, symbol];
+                    vec![Element::Rule(rule)]
+                }
+                Some('?') => {
+                    self.at += 1;
+                    vec![Element::Rule(self.anon_rule(vec![symbol, vec![]]))]
+                }
+                _ => symbol,
+            };
+            seq.extend(symbol);
+        }
+        Ok(seq)
+    }
+
+    /// Char after a backslash
+    fn parse_escape(&mut self) -> Result<char, String> {
+        let hex = |p: &mut Self, len: usize| -> Result<char, String> {
+            let digits: String = (0..len).map(|_| p.next()).collect::<Result<_, _>>()?;
+            u32::from_str_radix(&digits, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .map_or_else(|| p.err("Bad escape"), Ok)
+        };
+        match self.next()? {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            'x' => hex(self, 2),
+            'u' => hex(self, 4),
+            c => Ok(c),
+        }
+    }
+}
+
+#[derive(Default)]
+struct TrieNode {
+    children: Vec<(char, usize)>,
+    /// Tokens whose text ends here
+    tokens: Vec<usize>,
+}
+
+/// Vocab as a char trie, so masking walks shared token prefixes once
+pub struct TokenTrie {
+    nodes: Vec<TrieNode>,
+    texts: Vec<String>,
+}
+
+impl TokenTrie {
+    /// Special tokens and byte tokens that are not a full char are left out
+    pub fn new(vocab: &Vocab) -> Self {
+        let texts = (0..vocab.offsets.len() - 1)
+            .map(|idx| vocab.get_token(idx).to_string())
+            .collect::<Vec<_>>();
+        let mut trie = Self {
+            nodes: vec![TrieNode::default()],
+            texts: vec![],
+        };
+        for (idx, text) in texts.iter().enumerate() {
+            if idx < BYTE_OFFSET || (BYTE_OFFSET + 0x80..BYTE_OFFSET + 0x100).contains(&idx) {
+                continue;
+            }
+            let mut node = 0;
+            for c in text.chars() {
+                node = match trie.nodes[node].children.iter().find(|(ch, _)| *ch == c) {
+                    Some(&(_, child)) => child,
+                    None => {
+                        trie.nodes.push(TrieNode::default());
+                        let child = trie.nodes.len() - 1;
+                        trie.nodes[node].children.push((c, child));
+                        child
+                    }
+                };
+            }
+            if node != 0 {
+                trie.nodes[node].tokens.push(idx);
+            }
+        }
+        trie.texts = texts;
+        trie
+    }
+
+    pub fn text(&self, token: usize) -> &str {
+        &self.texts[token]
+    }
+
+    pub fn vocab_size(&self) -> usize {
+        self.texts.len()
+    }
+
+    /// Walk the trie, `step` maps a state and a char to the next state (`None` rejects).
+    /// Marks every token whose chars are all accepted.
+    pub fn visit<S>(
+        &self,
+        state: S,
+        step: &mut impl FnMut(&S, char) -> Option<S>,
+        allowed: &mut [bool],
+    ) {
+        self.visit_node(0, &state, step, allowed);
+    }
+
+    fn visit_node<S>(
+        &self,
+        node: usize,
+        state: &S,
+        step: &mut impl FnMut(&S, char) -> Option<S>,
+        allowed: &mut [bool],
+    ) {
+        let TrieNode { children, tokens } = &self.nodes[node];
+        tokens.iter().for_each(|&t| allowed[t] = true);
+        for &(c, child) in children {
+            if let Some(next) = step(state, c) {
+                self.visit_node(child, &next, step, allowed);
+            }
+        }
+    }
+}
+
+/// Set every logit that is not `allowed` to -inf. Allows EOS if nothing else is.
+pub fn mask_logits(allowed: &[bool], logits: &mut [Ty]) {
+    let any = allowed.iter().any(|&a| a);
+    for (idx, logit) in logits.iter_mut().enumerate() {
+        if !(allowed[idx] || (!any && idx == EOS)) {
+            *logit = Ty::NEG_INFINITY;
+        }
+    }
+}
+
+/// Grammar matcher with memoized transitions.
+/// Sets of stacks are interned as states, so the (many) repeated `accept` calls
+/// of a trie walk turn into lookups.
+struct Matcher {
+    grammar: Arc<Grammar>,
+    states: Vec<Vec<Stack>>,
+    ids: HashMap<Vec<Stack>, usize>,
+    transitions: HashMap<(usize, char), Option<usize>>,
+}
+
+impl Matcher {
+    fn intern(&mut self, stacks: Vec<Stack>) -> usize {
+        if let Some(&id) = self.ids.get(&stacks) {
+            return id;
+        }
+        self.states.push(stacks.clone());
+        self.ids.insert(stacks, self.states.len() - 1);
+        self.states.len() - 1
+    }
+
+    /// State after `c`, `None` if `c` is not allowed
+    fn advance(&mut self, state: usize, c: char) -> Option<usize> {
+        if let Some(&next) = self.transitions.get(&(state, c)) {
+            return next;
+        }
+        let stacks = self.grammar.accept(&self.states[state], c);
+        let next = (!stacks.is_empty()).then(|| self.intern(stacks));
+        self.transitions.insert((state, c), next);
+        next
+    }
+}
+
+/// Mask logits of tokens that would break the grammar, EOS is only allowed once the
+/// generated text is complete. Pair with a `StopTokens(vec![EOS])`.
+pub struct GrammarConstraint {
+    matcher: Matcher,
+    trie: Arc<TokenTrie>,
+    state: usize,
+    /// History length when generation started, the prompt is not constrained
+    start: Option<usize>,
+    /// Generated tokens fed to the matcher so far
+    consumed: usize,
+    /// Allowed tokens of recently seen states
+    masks: HashMap<usize, Vec<bool>>,
+}
+
+/// Max number of cached token masks (vocab_size bools each)
+const MAX_MASKS: usize = 64;
+
+impl GrammarConstraint {
+    pub fn new(grammar: Arc<Grammar>, trie: Arc<TokenTrie>) -> Self {
+        let mut matcher = Matcher {
+            grammar,
+            states: vec![],
+            ids: HashMap::new(),
+            transitions: HashMap::new(),
+        };
+        let state = matcher.intern(matcher.grammar.initial_stacks());
+        Self {
+            matcher,
+            trie,
+            state,
+            start: None,
+            consumed: 0,
+            masks: HashMap::new(),
+        }
+    }
+}
+
+impl LogitsProcessor for GrammarConstraint {
+    fn process(&mut self, history: &[usize], logits: &mut [Ty]) {
+        let start = *self.start.get_or_insert(history.len());
+        for &token in &history[start + self.consumed..] {
+            for c in self.trie.text(token).chars() {
+                // only allowed tokens get sampled, so this can't fail
+                self.state = self.matcher.advance(self.state, c).unwrap();
+            }
+            self.consumed += 1;
+        }
+
+        if !self.masks.contains_key(&self.state) {
+            if self.masks.len() >= MAX_MASKS {
+                self.masks.clear();
+            }
+            let mut allowed = vec![false; self.trie.texts.len()];
+            let matcher = &mut self.matcher;
+            self.trie
+                .visit(self.state, &mut |&s, c| matcher.advance(s, c), &mut allowed);
+            allowed[EOS] = Grammar::is_complete(&matcher.states[self.state]);
+            self.masks.insert(self.state, allowed);
+        }
+        mask_logits(&self.masks[&self.state], logits);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `None` if `text` breaks the grammar, else whether it is a complete sentence
+    fn check(grammar: &str, text: &str) -> Option<bool> {
+        let grammar = Grammar::parse(grammar).unwrap();
+        let mut stacks = grammar.initial_stacks();
+        for c in text.chars() {
+            stacks = grammar.accept(&stacks, c);
+            if stacks.is_empty() {
+                return None;
+            }
+        }
+        Some(Grammar::is_complete(&stacks))
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Grammar::parse("root ::= answer").is_err());
+        assert!(Grammar::parse("answer ::= \"yes\"").is_err());
+        assert!(Grammar::parse("root ::= \"a\"\nroot ::= \"b\"").is_err());
+        assert!(Grammar::parse("root ::= (\"a\"").is_err());
+        assert!(Grammar::parse("root ::= [a-z]+ # comment\n").is_ok());
+    }
+
+    #[test]
+    fn accept_and_complete() {
+        let grammar = r#"
+            root   ::= answer ("," ws answer)*
+            answer ::= "yes" | "no" | [0-9]+
+            ws     ::= [ \t\n]?
+        "#;
+        assert_eq!(check(grammar, "yes"), Some(true));
+        assert_eq!(check(grammar, "yes, 42,no"), Some(true));
+        assert_eq!(check(grammar, "ye"), Some(false));
+        assert_eq!(check(grammar, "yes,"), Some(false));
+        assert_eq!(check(grammar, "maybe"), None);
+        assert_eq!(check(grammar, "yes,,"), None);
+        assert_eq!(check(r#"root ::= [^"]* "\"""#, "a b\""), Some(true));
+        assert_eq!(check(r#"root ::= "x" . "y""#, "x\u{e9}y"), Some(true));
+    }
+
+    #[test]
+    fn repeated_empty_match() {
+        let grammar = "root ::= \"x\" ws* \"y\"\nws ::= [ \\t]*";
+        assert_eq!(check(grammar, "x"), Some(false));
+        assert_eq!(check(grammar, "x \t y"), Some(true));
+        assert_eq!(check(grammar, "xz"), None);
+        assert_eq!(check("root ::= (\"a\"?)*", ""), Some(true));
+        assert_eq!(check("root ::= (\"a\"?)*", "aaa"), Some(true));
+        assert_eq!(check("root ::= (\"a\"?)+ \"b\"", "aab"), Some(true));
+    }
+}
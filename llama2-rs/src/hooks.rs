@@ -0,0 +1,139 @@
+//! Activation hooks, to look at (or change) the intermediate tensors of the forward pass.
+//! [`LamaExecuter::step_hooked`](crate::LamaExecuter::step_hooked) hands each layer tensors to an
+//! [`ActivationHook`] as they are computed, changes to the residual stream carry on through the
+//! model.
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::{Config, Ty};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Stage {
+    /// Attention probabilities, (n_heads, seq_len), only positions up to `pos` are set
+    Attention,
+    /// FFN hidden activations `SiLU(w1 x) * (w3 x)`, (hidden_dim,)
+    FfnHidden,
+    /// Residual stream `x` after the layer, (dim,)
+    Residual,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 3] = [Stage::Attention, Stage::FfnHidden, Stage::Residual];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Attention => "attention",
+            Stage::FfnHidden => "ffn_hidden",
+            Stage::Residual => "residual",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|s| s.name() == name)
+    }
+}
+
+pub trait ActivationHook<Buffer> {
+    /// `tensor` of `stage` in `layer`, for the token at `pos`
+    fn on_activation(&mut self, layer: usize, pos: usize, stage: Stage, tensor: &mut Buffer);
+}
+
+/// Plain forward pass
+pub struct NoHook;
+
+impl<Buffer> ActivationHook<Buffer> for NoHook {
+    #[inline]
+    fn on_activation(&mut self, _: usize, _: usize, _: Stage, _: &mut Buffer) {}
+}
+
+/// One captured tensor
+#[derive(Debug, Clone)]
+pub struct Activation {
+    pub layer: usize,
+    pub pos: usize,
+    pub stage: Stage,
+    pub tensor: Vec<Ty>,
+}
+
+/// Copies the tensors of the chosen stages and layers (all layers when `layers` is empty)
+#[derive(Debug, Clone)]
+pub struct Capture {
+    n_heads: usize,
+    pub stages: Vec<Stage>,
+    pub layers: Vec<usize>,
+    pub activations: Vec<Activation>,
+}
+
+impl Capture {
+    pub fn new(cfg: &Config, stages: &[Stage], layers: &[usize]) -> Self {
+        Self {
+            n_heads: cfg.n_heads,
+            stages: stages.to_vec(),
+            layers: layers.to_vec(),
+            activations: vec![],
+        }
+    }
+}
+
+impl ActivationHook<Vec<Ty>> for Capture {
+    fn on_activation(&mut self, layer: usize, pos: usize, stage: Stage, tensor: &mut Vec<Ty>) {
+        if !self.stages.contains(&stage)
+            || !(self.layers.is_empty() || self.layers.contains(&layer))
+        {
+            return;
+        }
+        // attention rows past `pos` are leftovers of earlier tokens, zero them for a causal mask
+        let mut tensor = tensor.clone();
+        if stage == Stage::Attention {
+            let seq_len = tensor.len() / self.n_heads;
+            tensor
+                .chunks_exact_mut(seq_len)
+                .for_each(|head| head[pos + 1..].fill(0.));
+        }
+        self.activations.push(Activation {
+            layer,
+            pos,
+            stage,
+            tensor,
+        });
+    }
+}
+
+/// Write a little endian f32 array of `shape` in NumPy `.npy` format (version 1.0)
+pub fn write_npy(path: impl AsRef<Path>, shape: &[usize], data: &[f32]) -> io::Result<()> {
+    assert_eq!(
+        shape.iter().product::<usize>(),
+        data.len(),
+        "Shape doesn't match data"
+    );
+    let shape = match shape {
+        [n] => format!("({},)", n),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': {}, }}",
+        shape
+    );
+    // magic, version and header length take 10 bytes, the header ends in a newline at a
+    // multiple of 64
+    let total = (10 + header.len() + 1).div_ceil(64) * 64;
+    header.push_str(&" ".repeat(total - 10 - header.len() - 1));
+    header.push('\n');
+
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(b"\x93NUMPY\x01\x00")?;
+    out.write_all(&(header.len() as u16).to_le_bytes())?;
+    out.write_all(header.as_bytes())?;
+    for v in data {
+        out.write_all(&v.to_le_bytes())?;
+    }
+    out.flush()
+}
@@ -0,0 +1,305 @@
+//! JSON schema constrained generation.
+//! The schema is translated to a GBNF grammar that only accepts JSON documents valid under it,
+//! then decoding is constrained by [`crate::grammar::GrammarConstraint`].
+//!
+//! Supported: `type` (one or a list of object, array, string, number, integer, boolean, null),
+//! `properties` and `required` (properties come out in schema order), `items`, `minItems`,
+//! `maxItems`, `minLength`, `maxLength`, `enum`, `const`, `anyOf` and `oneOf`.
+//! A schema without a type (or `true`) accepts any JSON value.
+use serde_json::Value;
+
+use crate::grammar::Grammar;
+
+/// Rules shared by every schema
+const BASE_RULES: &str = r#"
+ws ::= " "?
+char ::= [^"\\\x00-\x1f] | "\\" (["\\/bfnrt] | "u" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F])
+string ::= "\"" char* "\""
+integer ::= "-"? ("0" | [1-9] [0-9]*)
+number ::= integer ("." [0-9]+)? ([eE] [-+]? [0-9]+)?
+boolean ::= "true" | "false"
+null ::= "null"
+value ::= object | array | string | number | boolean | null
+object ::= "{" ws (string ws ":" ws value ("," ws string ws ":" ws value)*)? ws "}"
+array ::= "[" ws (value ("," ws value)*)? ws "]"
+"#;
+
+/// Grammar accepting the JSON documents valid under `schema` (JSON text)
+pub fn grammar_from_schema(schema: &str) -> Result<Grammar, String> {
+    let schema: Value =
+        serde_json::from_str(schema).map_err(|e| format!("Bad JSON schema: {}", e))?;
+    Grammar::parse(&schema_to_gbnf(&schema)?)
+}
+
+/// GBNF source of the grammar accepting the JSON documents valid under `schema`
+pub fn schema_to_gbnf(schema: &Value) -> Result<String, String> {
+    let mut conv = Converter { rules: vec![] };
+    let root = conv.visit(schema)?;
+    let mut gbnf = format!("root ::= {}\n", root);
+    conv.rules.iter().for_each(|r| gbnf.push_str(r));
+    gbnf.push_str(BASE_RULES);
+    Ok(gbnf)
+}
+
+struct Converter {
+    rules: Vec<String>,
+}
+
+impl Converter {
+    /// New rule, returns its name
+    fn rule(&mut self, body: String) -> String {
+        let name = format!("schema{}", self.rules.len());
+        self.rules.push(format!("{} ::= {}\n", name, body));
+        name
+    }
+
+    /// Grammar expression matching the JSON values valid under `schema`
+    fn visit(&mut self, schema: &Value) -> Result<String, String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok("value".into()),
+            Value::Object(schema) => schema,
+            _ => return Err(format!("Unsupported schema {}", schema)),
+        };
+        if let Some(value) = schema.get("const") {
+            return Ok(json_literal(value));
+        }
+        if let Some(values) = schema.get("enum") {
+            let values = values.as_array().ok_or("enum must be an array")?;
+            let alts = values.iter().map(json_literal).collect::<Vec<_>>();
+            return Ok(self.rule(alts.join(" | ")));
+        }
+        if let Some(schemas) = schema.get("anyOf").or_else(|| schema.get("oneOf")) {
+            let schemas = schemas.as_array().ok_or("anyOf/oneOf must be an array")?;
+            let alts = schemas
+                .iter()
+                .map(|s| self.visit(s))
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(self.rule(alts.join(" | ")));
+        }
+
+        let types = match schema.get("type") {
+            Some(Value::String(t)) => vec![t.as_str()],
+            Some(Value::Array(ts)) => ts
+                .iter()
+                .map(|t| t.as_str().ok_or("type must be a string"))
+                .collect::<Result<_, _>>()?,
+            Some(t) => return Err(format!("Unsupported type {}", t)),
+            None if schema.contains_key("properties") => vec!["object"],
+            None if schema.contains_key("items") => vec!["array"],
+            None => return Ok("value".into()),
+        };
+        let alts = types
+            .into_iter()
+            .map(|t| match t {
+                "object" => self.object(schema),
+                "array" => self.array(schema),
+                "string" => self.string(schema),
+                "integer" | "number" | "boolean" | "null" => Ok(t.to_string()),
+                _ => Err(format!("Unsupported type {}", t)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(match alts.len() {
+            1 => alts.into_iter().next().unwrap(),
+            _ => self.rule(alts.join(" | ")),
+        })
+    }
+
+    fn object(&mut self, schema: &serde_json::Map<String, Value>) -> Result<String, String> {
+        let Some(props) = schema.get("properties") else {
+            return Ok("object".into());
+        };
+        let props = props.as_object().ok_or("properties must be an object")?;
+        let required = match schema.get("required") {
+            Some(req) => req
+                .as_array()
+                .ok_or("required must be an array")?
+                .iter()
+                .map(|r| r.as_str().ok_or("required must hold strings"))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => vec![],
+        };
+        if let Some(missing) = required.iter().find(|r| !props.contains_key(**r)) {
+            return Err(format!(
+                "Required property {} is not in properties",
+                missing
+            ));
+        }
+
+        let mut pairs = vec![];
+        for (name, prop) in props {
+            let value = self.visit(prop)?;
+            let key = json_literal(&Value::String(name.clone()));
+            pairs.push((
+                format!("{} ws \":\" ws {}", key, value),
+                required.contains(&name.as_str()),
+            ));
+        }
+
+        // Properties i.. , `first` when nothing was written yet (no leading comma).
+        // Built back to front, each rule refers to the ones after it.
+        let (mut first, mut rest) = (String::from("\"\""), String::from("\"\""));
+        for (pair, required) in pairs.into_iter().rev() {
+            let (new_first, new_rest) = if required {
+                (
+                    format!("{} {}", pair, rest),
+                    format!("\",\" ws {} {}", pair, rest),
+                )
+            } else {
+                (
+                    format!("{} {} | {}", pair, rest, first),
+                    format!("(\",\" ws {})? {}", pair, rest),
+                )
+            };
+            first = self.rule(new_first);
+            rest = self.rule(new_rest);
+        }
+        Ok(self.rule(format!("\"{{\" ws {} ws \"}}\"", first)))
+    }
+
+    fn array(&mut self, schema: &serde_json::Map<String, Value>) -> Result<String, String> {
+        let item = match schema.get("items") {
+            Some(items) => self.visit(items)?,
+            None => "value".into(),
+        };
+        let (min, max) = bounds(schema, "minItems", "maxItems")?;
+        let items = self.repeat(&item, "\",\" ws", min, max);
+        Ok(self.rule(format!("\"[\" ws {} ws \"]\"", items)))
+    }
+
+    fn string(&mut self, schema: &serde_json::Map<String, Value>) -> Result<String, String> {
+        let (min, max) = bounds(schema, "minLength", "maxLength")?;
+        if (min, max) == (0, None) {
+            return Ok("string".into());
+        }
+        let chars = self.repeat("char", "", min, max);
+        Ok(self.rule(format!("\"\\\"\" {} \"\\\"\"", chars)))
+    }
+
+    /// `item` repeated `min..=max` times with `sep` between items
+    fn repeat(&mut self, item: &str, sep: &str, min: usize, max: Option<usize>) -> String {
+        if max == Some(0) {
+            return "\"\"".into();
+        }
+        if min == 0 {
+            let some = self.repeat(item, sep, 1, max);
+            return format!("({})?", some);
+        }
+        let next = format!("{} {}", sep, item);
+        let mut seq = vec![item.to_string()];
+        seq.extend((1..min).map(|_| next.clone()));
+        match max {
+            None => seq.push(format!("({})*", next)),
+            Some(max) => {
+                // (sep item (sep item (...)?)?)?
+                let mut tail = String::from("\"\"");
+                for _ in min..max {
+                    tail = self.rule(format!("({} {})?", next, tail));
+                }
+                seq.push(tail);
+            }
+        }
+        seq.join(" ")
+    }
+}
+
+fn bounds(
+    schema: &serde_json::Map<String, Value>,
+    min: &str,
+    max: &str,
+) -> Result<(usize, Option<usize>), String> {
+    let get = |key: &str| match schema.get(key) {
+        Some(v) => v
+            .as_u64()
+            .map(|v| Some(v as usize))
+            .ok_or(format!("{} must be a non negative integer", key)),
+        None => Ok(None),
+    };
+    let (min, max) = (get(min)?.unwrap_or(0), get(max)?);
+    if max.is_some_and(|max| max < min) {
+        return Err(format!("{} is smaller than {}", max.unwrap(), min));
+    }
+    Ok((min, max))
+}
+
+/// GBNF literal matching exactly the JSON encoding of `value`
+fn json_literal(value: &Value) -> String {
+    let mut lit = String::from("\"");
+    for c in value.to_string().chars() {
+        match c {
+            '"' => lit.push_str("\\\""),
+            '\\' => lit.push_str("\\\\"),
+            c if (c as u32) < 0x20 => lit.push_str(&format!("\\x{:02x}", c as u32)),
+            c => lit.push(c),
+        }
+    }
+    lit.push('"');
+    lit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(schema: &str, doc: &str) -> bool {
+        let grammar = grammar_from_schema(schema).unwrap();
+        let mut stacks = grammar.initial_stacks();
+        for c in doc.chars() {
+            stacks = grammar.accept(&stacks, c);
+        }
+        Grammar::is_complete(&stacks)
+    }
+
+    #[test]
+    fn gbnf_parses() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": { "tags": { "type": "array", "items": { "type": "string" } } }
+        });
+        let gbnf = schema_to_gbnf(&schema).unwrap();
+        assert!(gbnf.starts_with("root ::= "));
+        assert!(Grammar::parse(&gbnf).is_ok());
+        assert!(schema_to_gbnf(&serde_json::json!({ "type": "date" })).is_err());
+        assert!(grammar_from_schema(r#"{"properties": {}, "required": ["a"]}"#).is_err());
+    }
+
+    #[test]
+    fn properties() {
+        let schema = r#"{
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "age": { "type": "integer" },
+                "admin": { "type": "boolean" }
+            },
+            "required": ["name"]
+        }"#;
+        assert!(matches(schema, r#"{"name": "Ann"}"#));
+        assert!(matches(schema, r#"{"name": "Ann", "age": 42}"#));
+        assert!(matches(
+            schema,
+            r#"{ "name": "Ann","age": -1,"admin": true }"#
+        ));
+        assert!(matches(schema, r#"{"name": "Ann", "admin": false}"#));
+        // required missing, out of order, wrong type, unknown property
+        assert!(!matches(schema, r#"{"age": 42}"#));
+        assert!(!matches(schema, r#"{"age": 42, "name": "Ann"}"#));
+        assert!(!matches(schema, r#"{"name": "Ann", "age": 4.2}"#));
+        assert!(!matches(schema, r#"{"name": "Ann", "id": 1}"#));
+    }
+
+    #[test]
+    fn items_and_enum() {
+        let schema = r#"{
+            "type": "array",
+            "items": { "enum": ["red", "green", 3] },
+            "minItems": 1,
+            "maxItems": 2
+        }"#;
+        assert!(matches(schema, r#"["red"]"#));
+        assert!(matches(schema, r#"["green", 3]"#));
+        assert!(!matches(schema, "[]"));
+        assert!(!matches(schema, r#"["red", "red", "red"]"#));
+        assert!(!matches(schema, r#"["blue"]"#));
+        assert!(!matches(schema, r#"["3"]"#));
+    }
+}
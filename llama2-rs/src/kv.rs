@@ -0,0 +1,464 @@
+//! Paged K, V cache.
+//! K, V live in fixed size blocks from a pool shared by all sequences. Each sequence keeps a block
+//! table (block index per `BLOCK_SIZE` positions), so memory grows with the tokens actually
+//! cached and sequences with a common prefix can point at the same blocks.
+//!
+//! Blocks can be stored as f16 or int8 to save memory on long contexts,
+//! attention dequantizes them on the fly.
+//!
+//! The pool lock only guards the block bookkeeping. Each block has its own lock, so sequences
+//! run their forward passes concurrently. Attention read locks the blocks of a sequence once,
+//! through a [`KVReader`].
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+
+use crate::{Config, DefualtBuffer, Ty};
+
+/// Number of token positions in a block
+pub const BLOCK_SIZE: usize = 16;
+
+/// How K, V rows are stored in the pool
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KVFormat {
+    F32,
+    F16,
+    /// int8 with one scale per token row
+    Int8PerToken,
+    /// int8 with one scale per head of each token row
+    Int8PerHead,
+}
+
+impl FromStr for KVFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f32" => Ok(Self::F32),
+            "f16" => Ok(Self::F16),
+            "int8" => Ok(Self::Int8PerToken),
+            "int8-head" => Ok(Self::Int8PerHead),
+            _ => Err(format!(
+                "Unknown K, V cache format {}, expected f32, f16, int8 or int8-head",
+                s
+            )),
+        }
+    }
+}
+
+/// Storage of a single block, rows are (n_layers, 2, BLOCK_SIZE, dim)
+#[derive(Clone)]
+enum Block<Buffer> {
+    F32(Buffer),
+    F16(Vec<u16>),
+    /// Quantized rows and their scales (n_layers, 2, BLOCK_SIZE, groups)
+    Int8(Vec<i8>, Vec<Ty>),
+}
+
+pub struct BlockPool<Buffer> {
+    /// Allocated on first use
+    blocks: Vec<Arc<RwLock<Block<Buffer>>>>,
+    /// Number of block tables (and caches) holding each block
+    refs: Vec<usize>,
+    free: Vec<usize>,
+    max_blocks: usize,
+    format: KVFormat,
+    n_layers: usize,
+    dim: usize,
+    head_size: usize,
+}
+
+pub type SharedPool = Arc<Mutex<BlockPool<Vec<Ty>>>>;
+
+impl<Buffer: DefualtBuffer> BlockPool<Buffer> {
+    pub fn new(cfg: &Config, max_blocks: usize, format: KVFormat) -> Self {
+        Self {
+            blocks: vec![],
+            refs: vec![],
+            free: vec![],
+            max_blocks,
+            format,
+            n_layers: cfg.n_layers,
+            dim: cfg.dim,
+            head_size: cfg.dim / cfg.n_heads,
+        }
+    }
+
+    /// Get an unused block (with a single reference), `None` if the pool is exhausted
+    pub fn alloc(&mut self) -> Option<usize> {
+        let block = match self.free.pop() {
+            Some(block) => block,
+            None if self.blocks.len() < self.max_blocks => {
+                let rows = self.n_layers * 2 * BLOCK_SIZE;
+                let block = match self.format {
+                    KVFormat::F32 => Block::F32(Buffer::zeros(rows * self.dim)),
+                    KVFormat::F16 => Block::F16(vec![0; rows * self.dim]),
+                    KVFormat::Int8PerToken | KVFormat::Int8PerHead => Block::Int8(
+                        vec![0; rows * self.dim],
+                        vec![0 as Ty; rows * self.groups()],
+                    ),
+                };
+                self.blocks.push(Arc::new(RwLock::new(block)));
+                self.refs.push(0);
+                self.blocks.len() - 1
+            }
+            None => return None,
+        };
+        self.refs[block] = 1;
+        Some(block)
+    }
+
+    pub fn retain(&mut self, block: usize) {
+        self.refs[block] += 1;
+    }
+
+    pub fn release(&mut self, block: usize) {
+        self.refs[block] -= 1;
+        if self.refs[block] == 0 {
+            self.free.push(block);
+        }
+    }
+
+    pub fn refs(&self, block: usize) -> usize {
+        self.refs[block]
+    }
+
+    /// Blocks held by some block table (or cache)
+    pub fn used(&self) -> usize {
+        self.blocks.len() - self.free.len()
+    }
+
+    /// Number of quantization groups (scales) in a row
+    fn groups(&self) -> usize {
+        match self.format {
+            KVFormat::Int8PerHead => self.dim / self.head_size,
+            _ => 1,
+        }
+    }
+}
+
+impl BlockPool<Vec<Ty>> {
+    pub fn copy_block(&mut self, src: usize, dst: usize) {
+        let block = self.blocks[src].read().unwrap().clone();
+        *self.blocks[dst].write().unwrap() = block;
+    }
+
+    /// K, V of the sequence with block `table`, usable once the pool is unlocked
+    pub fn paged(&self, table: &[usize]) -> PagedKV<Vec<Ty>> {
+        PagedKV {
+            blocks: table.iter().map(|&b| Arc::clone(&self.blocks[b])).collect(),
+            dim: self.dim,
+            head_size: self.head_size,
+            groups: self.groups(),
+        }
+    }
+}
+
+/// Index of a row in a block
+#[inline]
+fn row_idx(layer: usize, is_v: usize, slot: usize) -> usize {
+    (layer * 2 + is_v) * BLOCK_SIZE + slot
+}
+
+/// Number of blocks needed to hold `tokens` positions
+pub fn blocks_for(tokens: usize) -> usize {
+    tokens.div_ceil(BLOCK_SIZE)
+}
+
+/// K, V of a single sequence, its blocks in block table order
+pub struct PagedKV<Buffer> {
+    blocks: Vec<Arc<RwLock<Block<Buffer>>>>,
+    dim: usize,
+    head_size: usize,
+    groups: usize,
+}
+
+impl PagedKV<Vec<Ty>> {
+    /// Read access to every cached block, for one attention call
+    pub fn read(&self) -> KVReader<'_> {
+        KVReader {
+            blocks: self.blocks.iter().map(|b| b.read().unwrap()).collect(),
+            dim: self.dim,
+            head_size: self.head_size,
+            groups: self.groups,
+        }
+    }
+
+    /// Cache K, V of `layer` at `pos`. The block must be owned by this sequence only.
+    pub fn write(&mut self, layer: usize, pos: usize, k: &[Ty], v: &[Ty]) {
+        let (groups, dim) = (self.groups, self.dim);
+        let mut block = self.blocks[pos / BLOCK_SIZE].write().unwrap();
+        for (is_v, src) in [k, v].into_iter().enumerate() {
+            let row = row_idx(layer, is_v, pos % BLOCK_SIZE);
+            let st = row * dim;
+            match &mut *block {
+                Block::F32(data) => data[st..st + dim].copy_from_slice(src),
+                Block::F16(data) => data[st..st + dim]
+                    .iter_mut()
+                    .zip(src)
+                    .for_each(|(dst, &v)| *dst = f32_to_f16(v)),
+                Block::Int8(data, scales) => {
+                    let group_size = dim / groups;
+                    let dst = data[st..st + dim].chunks_exact_mut(group_size);
+                    let scales = scales[row * groups..(row + 1) * groups].iter_mut();
+                    for ((dst, src), scale) in dst.zip(src.chunks_exact(group_size)).zip(scales) {
+                        let max = src.iter().fold(0 as Ty, |acc, v| acc.max(v.abs()));
+                        *scale = max / 127 as Ty;
+                        let inv = if max > 0 as Ty {
+                            1 as Ty / *scale
+                        } else {
+                            0 as Ty
+                        };
+                        dst.iter_mut()
+                            .zip(src)
+                            .for_each(|(q, &v)| *q = (v * inv).round() as i8);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Cached K, V of a sequence, read locked
+pub struct KVReader<'a> {
+    blocks: Vec<RwLockReadGuard<'a, Block<Vec<Ty>>>>,
+    dim: usize,
+    head_size: usize,
+    groups: usize,
+}
+
+/// A head of a cached row, as stored
+enum HeadRow<'a> {
+    F32(&'a [Ty]),
+    F16(&'a [u16]),
+    /// Quantized values and their scale
+    Int8(&'a [i8], Ty),
+}
+
+impl KVReader<'_> {
+    /// Head `h` of a cached row
+    #[inline]
+    fn head_row(&self, layer: usize, is_v: usize, pos: usize, h: usize) -> HeadRow<'_> {
+        let row = row_idx(layer, is_v, pos % BLOCK_SIZE);
+        let hs = self.head_size;
+        let st = row * self.dim + h * hs;
+        match &*self.blocks[pos / BLOCK_SIZE] {
+            Block::F32(data) => HeadRow::F32(&data[st..st + hs]),
+            Block::F16(data) => HeadRow::F16(&data[st..st + hs]),
+            Block::Int8(data, scales) => {
+                let groups = self.groups;
+                let group = if groups == 1 { 0 } else { h };
+                HeadRow::Int8(&data[st..st + hs], scales[row * groups + group])
+            }
+        }
+    }
+
+    /// <q, K> of head `h` cached for `layer` at `pos`
+    #[inline]
+    pub fn dot_k(&self, layer: usize, pos: usize, h: usize, q: &[Ty]) -> Ty {
+        match self.head_row(layer, 0, pos, h) {
+            HeadRow::F32(k) => k.iter().zip(q).fold(0 as Ty, |acc, (k, q)| acc + k * q),
+            HeadRow::F16(k) => k
+                .iter()
+                .zip(q)
+                .fold(0 as Ty, |acc, (&k, q)| acc + f16_to_f32(k) * q),
+            HeadRow::Int8(k, scale) => {
+                scale
+                    * k.iter()
+                        .zip(q)
+                        .fold(0 as Ty, |acc, (&k, q)| acc + k as Ty * q)
+            }
+        }
+    }
+
+    /// dst += weight * V of head `h` cached for `layer` at `pos`
+    #[inline]
+    pub fn add_v(&self, layer: usize, pos: usize, h: usize, weight: Ty, dst: &mut [Ty]) {
+        match self.head_row(layer, 1, pos, h) {
+            HeadRow::F32(v) => dst.iter_mut().zip(v).for_each(|(d, v)| *d += v * weight),
+            HeadRow::F16(v) => dst
+                .iter_mut()
+                .zip(v)
+                .for_each(|(d, &v)| *d += f16_to_f32(v) * weight),
+            HeadRow::Int8(v, scale) => {
+                let weight = weight * scale;
+                dst.iter_mut()
+                    .zip(v)
+                    .for_each(|(d, &v)| *d += v as Ty * weight)
+            }
+        }
+    }
+}
+
+/// Round to nearest even f32 -> IEEE half precision bits
+fn f32_to_f16(v: f32) -> u16 {
+    let x = v.to_bits();
+    let sign = ((x >> 16) & 0x8000) as u16;
+    let exp = ((x >> 23) & 0xff) as i32;
+    let man = x & 0x7f_ffff;
+    if exp == 0xff {
+        // inf / nan
+        return sign | 0x7c00 | if man != 0 { 0x200 } else { 0 };
+    }
+    let e = exp - 127 + 15;
+    if e >= 0x1f {
+        return sign | 0x7c00;
+    }
+    // keep `keep` bits of the (implicit 1 included) mantissa, round the rest
+    let (man, shift, bias) = if e <= 0 {
+        if e < -10 {
+            return sign;
+        }
+        (man | 0x80_0000, (14 - e) as u32, 0)
+    } else {
+        (man, 13, (e as u32) << 10)
+    };
+    let half = man >> shift;
+    let rem = man & ((1 << shift) - 1);
+    let halfway = 1 << (shift - 1);
+    let round = (rem > halfway || (rem == halfway && half & 1 == 1)) as u32;
+    // a mantissa carry correctly bumps the exponent
+    sign | (bias + half + round) as u16
+}
+
+/// IEEE half precision bits -> f32
+fn f16_to_f32(h: u16) -> f32 {
+    let sign = ((h & 0x8000) as u32) << 16;
+    let exp = ((h >> 10) & 0x1f) as u32;
+    let man = (h & 0x3ff) as u32;
+    let bits = match exp {
+        0 if man == 0 => sign,
+        0 => {
+            // subnormal
+            let v = man as f32 * 2f32.powi(-24);
+            return if sign != 0 { -v } else { v };
+        }
+        0x1f => sign | 0x7f80_0000 | (man << 13),
+        _ => sign | ((exp + 127 - 15) << 23) | (man << 13),
+    };
+    f32::from_bits(bits)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::session::Session;
+    use crate::{LayerWeights, Llama2CPUFloat};
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    pub(crate) fn random_model(cfg: &Config) -> Llama2CPUFloat {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut w = |n: usize| {
+            (0..n)
+                .map(|_| rng.gen_range(-0.1..0.1))
+                .collect::<Vec<Ty>>()
+        };
+        let head_size = cfg.dim / cfg.n_heads;
+        let (mut rope_real, mut rope_imag) = (vec![], vec![]);
+        for pos in 0..cfg.seq_len {
+            for i in 0..head_size / 2 {
+                let freq = 1.0 / (10000 as Ty).powf((2 * i) as Ty / head_size as Ty);
+                rope_real.push((pos as Ty * freq).cos());
+                rope_imag.push((pos as Ty * freq).sin());
+            }
+        }
+        Llama2CPUFloat {
+            embeddings: w(cfg.vocab_size * cfg.dim),
+            layers: (0..cfg.n_layers)
+                .map(|_| LayerWeights {
+                    rms_attn: vec![1 as Ty; cfg.dim],
+                    rms_ffn: vec![1 as Ty; cfg.dim],
+                    wq: w(cfg.dim * cfg.dim),
+                    wk: w(cfg.dim * cfg.dim),
+                    wv: w(cfg.dim * cfg.dim),
+                    wo: w(cfg.dim * cfg.dim),
+                    w1: w(cfg.hidden_dim * cfg.dim),
+                    w2: w(cfg.dim * cfg.hidden_dim),
+                    w3: w(cfg.hidden_dim * cfg.dim),
+                    patch: Default::default(),
+                })
+                .collect(),
+            rms_final: vec![1 as Ty; cfg.dim],
+            rope_real,
+            rope_imag,
+            wcls: None,
+            patch: Default::default(),
+        }
+    }
+
+    /// Max abs logit difference (over all steps) between `format` and the f32 cache,
+    /// relative to the largest f32 cache logit
+    fn logit_drift(format: KVFormat) -> Ty {
+        let cfg = Config {
+            dim: 64,
+            hidden_dim: 172,
+            n_layers: 2,
+            n_heads: 4,
+            n_kv_heads: 4,
+            vocab_size: 256,
+            seq_len: 64,
+            shared_weights: false,
+        };
+        let weights = random_model(&cfg);
+        let pool = |format| {
+            Arc::new(Mutex::new(BlockPool::new(
+                &cfg,
+                blocks_for(cfg.seq_len),
+                format,
+            )))
+        };
+        let (ref_pool, pool) = (pool(KVFormat::F32), pool(format));
+        let mut reference = Session::new(&cfg, &ref_pool);
+        let mut session = Session::new(&cfg, &pool);
+
+        let (mut drift, mut scale) = (0 as Ty, 0 as Ty);
+        for pos in 0..cfg.seq_len {
+            let token = (pos * 37) % cfg.vocab_size;
+            reference.feed(&weights, &cfg, token).unwrap();
+            session.feed(&weights, &cfg, token).unwrap();
+            for (a, b) in reference
+                .state
+                .logits
+                .iter()
+                .zip(session.state.logits.iter())
+            {
+                drift = drift.max((a - b).abs());
+                scale = scale.max(a.abs());
+            }
+        }
+        drift / scale
+    }
+
+    #[test]
+    fn quantized_cache_logit_drift() {
+        assert_eq!(logit_drift(KVFormat::F32), 0 as Ty);
+        let f16 = logit_drift(KVFormat::F16);
+        let per_head = logit_drift(KVFormat::Int8PerHead);
+        let per_token = logit_drift(KVFormat::Int8PerToken);
+        assert!(f16 < 1e-3, "relative logit drift f16: {:e}", f16);
+        assert!(
+            per_head < 1e-2,
+            "relative logit drift int8 per head: {:e}",
+            per_head
+        );
+        assert!(
+            per_token < 1e-2,
+            "relative logit drift int8 per token: {:e}",
+            per_token
+        );
+    }
+
+    #[test]
+    fn f16_round_trip() {
+        for v in [0.0, -0.0, 1.0, -2.5, 65504.0, 6.1e-5, 3.0e-7, 0.1] {
+            let back = f16_to_f32(f32_to_f16(v));
+            assert!(
+                (back - v).abs() <= v.abs() * 1e-3 + 6e-8,
+                "{} -> {}",
+                v,
+                back
+            );
+        }
+        assert!(f16_to_f32(f32_to_f16(1e6)).is_infinite());
+    }
+}
@@ -0,0 +1,51 @@
+//! Logit lens: what the model would predict if it stopped after each layer, by running the
+//! intermediate residual stream through the final norm and classifier.
+use crate::hooks::{Capture, Stage};
+use crate::kv::SharedPool;
+use crate::sampling::{log_softmax, top_logprobs};
+use crate::session::{Session, POOL_EXHAUSTED};
+use crate::{Config, EmbeddingTable, LLamaLayer, LlamaWeights, RMSNormWeight, Ty};
+
+/// Top predictions after each layer for one position
+#[derive(Debug, Clone)]
+pub struct LensPosition {
+    /// Token fed at this position
+    pub token: usize,
+    /// By layer, `(token, logprob)` most likely first. The last layer is the model output.
+    pub layers: Vec<Vec<(usize, Ty)>>,
+}
+
+/// The `top_k` next token predictions of every layer at every position of `tokens`
+pub fn logit_lens<L, Rms, Emb>(
+    weights: &LlamaWeights<L, Rms, Emb, Vec<Ty>>,
+    cfg: &Config,
+    pool: &SharedPool,
+    tokens: &[usize],
+    top_k: usize,
+) -> Vec<LensPosition>
+where
+    L: LLamaLayer<Vec<Ty>>,
+    Rms: RMSNormWeight<Vec<Ty>>,
+    Emb: EmbeddingTable<Vec<Ty>>,
+{
+    let mut session = Session::new(cfg, pool);
+    let mut logits = vec![0 as Ty; cfg.vocab_size];
+    tokens
+        .iter()
+        .map(|&token| {
+            let mut capture = Capture::new(cfg, &[Stage::Residual], &[]);
+            session
+                .feed_hooked(weights, cfg, token, &mut capture)
+                .expect(POOL_EXHAUSTED);
+            let layers = capture
+                .activations
+                .iter()
+                .map(|a| {
+                    weights.classify(&a.tensor, &mut logits);
+                    top_logprobs(&log_softmax(&logits), top_k)
+                })
+                .collect();
+            LensPosition { token, layers }
+        })
+        .collect()
+}
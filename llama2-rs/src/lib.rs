@@ -0,0 +1,893 @@
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::mem;
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+};
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+pub mod batch;
+pub mod bench;
+pub mod beam;
+pub mod chat;
+pub mod eval;
+pub mod grammar;
+pub mod hooks;
+pub mod json_schema;
+pub mod kv;
+pub mod lens;
+pub mod lora;
+pub mod patch;
+pub mod profile;
+pub mod regex;
+pub mod sampling;
+pub mod scheduler;
+pub mod server;
+pub mod session;
+pub mod steering;
+pub mod text;
+use hooks::{ActivationHook, NoHook, Stage};
+use kv::PagedKV;
+use patch::{LayerPatch, ModelPatch};
+use profile::Op;
+
+const CONF_VALS: usize = 7;
+const CONF_SIZE: usize = std::mem::size_of::<[i32; CONF_VALS]>();
+
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    pub dim: usize,
+    pub hidden_dim: usize,
+    pub n_layers: usize,
+    pub n_heads: usize,
+    pub n_kv_heads: usize,
+    pub vocab_size: usize,
+    pub seq_len: usize,
+    pub shared_weights: bool,
+}
+
+/// Exexute LLama step
+pub trait LamaExecuter<Buffer> {
+    fn step(
+        &self,
+        token: usize,
+        pos: usize,
+        cfg: &Config,
+        state: &mut ExecutionState<Buffer>,
+        kv: &mut PagedKV<Buffer>,
+    ) {
+        self.step_hooked(token, pos, cfg, state, kv, &mut NoHook);
+    }
+    /// Step, handing intermediate tensors to `hook`
+    fn step_hooked(
+        &self,
+        token: usize,
+        pos: usize,
+        cfg: &Config,
+        state: &mut ExecutionState<Buffer>,
+        kv: &mut PagedKV<Buffer>,
+        hook: &mut dyn ActivationHook<Buffer>,
+    );
+}
+
+/// Executte Llama layer
+pub trait LLamaLayer<Buffer> {
+    /// RMS norm residual stream and get Q,K,V matrices
+    fn rms_and_qkv(&self, cfg: &Config, state: &mut ExecutionState<Buffer>);
+    /// Rotate q and k heads according to position in seq (RoPE)
+    fn rope(
+        &self,
+        pos: usize,
+        cfg: &Config,
+        state: &mut ExecutionState<Buffer>,
+        rope_imag: &Buffer,
+        rope_real: &Buffer,
+    );
+    /// Cache sequence of K, V (to be used for attention computation)
+    fn cache_kv(
+        &self,
+        layer: usize,
+        pos: usize,
+        cfg: &Config,
+        state: &ExecutionState<Buffer>,
+        kv: &mut PagedKV<Buffer>,
+    );
+    /// (per head) Calculate Attention weights, accumulate value according to weights
+    fn attention(
+        &self,
+        layer: usize,
+        pos: usize,
+        cfg: &Config,
+        state: &ExecutionState<Buffer>,
+        kv: &PagedKV<Buffer>,
+    );
+    /// Merge all heads and add result to residula stream
+    fn merge_heads_to_resid_stream(&self, state: &mut ExecutionState<Buffer>);
+    /// RMS norm residual stream,
+    /// apply FeedForward to normalized
+    /// add to residual stream
+    fn ffn(&self, state: &mut ExecutionState<Buffer>);
+}
+
+pub trait LinearWeight<T> {
+    fn mat_vec(&self, vec: &T, dst: &mut T);
+}
+pub trait RMSNormWeight<T> {
+    fn rms_norm(&self, vec: &T, out: &mut T);
+    fn inplace_rms_norm(&self, vec: &mut T);
+}
+
+pub trait EmbeddingTable<Buf>: LinearWeight<Buf> {
+    fn token_to_resid_stream(&self, token: usize, dst: &mut Buf, cfg: &Config);
+}
+
+// Borrowed weights work as their owner, so models can share them (e.g. LoRA adapters)
+impl<T, W: LinearWeight<T>> LinearWeight<T> for &W {
+    fn mat_vec(&self, vec: &T, dst: &mut T) {
+        (**self).mat_vec(vec, dst)
+    }
+}
+
+impl<T, W: RMSNormWeight<T>> RMSNormWeight<T> for &W {
+    fn rms_norm(&self, vec: &T, out: &mut T) {
+        (**self).rms_norm(vec, out)
+    }
+    fn inplace_rms_norm(&self, vec: &mut T) {
+        (**self).inplace_rms_norm(vec)
+    }
+}
+
+impl<Buf, W: EmbeddingTable<Buf>> EmbeddingTable<Buf> for &W {
+    fn token_to_resid_stream(&self, token: usize, dst: &mut Buf, cfg: &Config) {
+        (**self).token_to_resid_stream(token, dst, cfg)
+    }
+}
+
+impl<Buffer, W: LamaExecuter<Buffer> + ?Sized> LamaExecuter<Buffer> for &W {
+    fn step_hooked(
+        &self,
+        token: usize,
+        pos: usize,
+        cfg: &Config,
+        state: &mut ExecutionState<Buffer>,
+        kv: &mut PagedKV<Buffer>,
+        hook: &mut dyn ActivationHook<Buffer>,
+    ) {
+        (**self).step_hooked(token, pos, cfg, state, kv, hook)
+    }
+}
+
+impl<Buffer, W: LamaExecuter<Buffer> + ?Sized> LamaExecuter<Buffer> for Box<W> {
+    fn step_hooked(
+        &self,
+        token: usize,
+        pos: usize,
+        cfg: &Config,
+        state: &mut ExecutionState<Buffer>,
+        kv: &mut PagedKV<Buffer>,
+        hook: &mut dyn ActivationHook<Buffer>,
+    ) {
+        (**self).step_hooked(token, pos, cfg, state, kv, hook)
+    }
+}
+
+pub struct LlamaWeights<Layer, Rms, Emb, Buf> {
+    /// (vocab_size, dim)
+    embeddings: Emb,
+    layers: Vec<Layer>,
+    /// (dim,)
+    rms_final: Rms,
+    /// (seq_len, head_size/2)
+    rope_real: Buf,
+    /// (seq_len, head_size/2)
+    rope_imag: Buf,
+    wcls: Option<Emb>,
+    patch: ModelPatch,
+}
+
+pub struct LayerWeights<Lin, Rms> {
+    rms_attn: Rms,
+    rms_ffn: Rms,
+    wq: Lin,
+    wk: Lin,
+    wv: Lin,
+    wo: Lin,
+    w1: Lin,
+    w2: Lin,
+    w3: Lin,
+    patch: LayerPatch,
+}
+
+pub type Ty = f32;
+pub type CPULayerFloat = LayerWeights<Vec<Ty>, Vec<Ty>>;
+pub type Llama2CPUFloat = LlamaWeights<CPULayerFloat, Vec<Ty>, Vec<Ty>, Vec<Ty>>;
+
+#[derive(Clone)]
+pub struct ExecutionState<Buffer> {
+    /// Shape:(dim,)
+    x: Buffer,   
+    /// Shape:(dim,)
+    xb: Buffer, 
+    /// Shape:(dim,)
+    xb2: Buffer,
+    /// Shape:(hidden_dim,)
+    h1: Buffer,
+    /// Shape:(hidden_dim,)
+    h2: Buffer,
+    /// (dim,): Q, buffers
+    q: Buffer,
+    /// (dim,): K buffer
+    k: Buffer,
+    /// (dim,): V buffer 
+    v: Buffer,
+    /// (n_heads, seq_len): Attention Weight Buffer
+    att: Buffer,
+    /// Logits: (vocab_size, )
+    pub logits: Buffer,
+}
+
+// f32 CPU implementation of Llama2
+impl<L, Rms, Emb> LamaExecuter<Vec<Ty>> for LlamaWeights<L, Rms, Emb, Vec<Ty>>
+where
+    L: LLamaLayer<Vec<Ty>>,
+    Rms: RMSNormWeight<Vec<Ty>>,
+    Emb: EmbeddingTable<Vec<Ty>>,
+{
+    fn step_hooked(
+        &self,
+        token: usize,
+        pos: usize,
+        cfg: &Config,
+        state: &mut ExecutionState<Vec<Ty>>,
+        kv: &mut PagedKV<Vec<Ty>>,
+        hook: &mut dyn ActivationHook<Vec<Ty>>,
+    ) {
+        // copy token embedding to residual stream
+        self.embeddings
+            .token_to_resid_stream(token, &mut state.x, cfg);
+
+        for (l, ld) in self.layers.iter().enumerate() {
+            if self.patch.skip_layers.contains(&l) {
+                continue;
+            }
+            let layer = Some(l);
+            profile::time(Op::RmsAndQkv, layer, || ld.rms_and_qkv(cfg, state));
+            profile::time(Op::Rope, layer, || {
+                ld.rope(pos, cfg, state, &self.rope_imag, &self.rope_real)
+            });
+            profile::time(Op::CacheKv, layer, || ld.cache_kv(l, pos, cfg, state, kv));
+            profile::time(Op::Attention, layer, || ld.attention(l, pos, cfg, state, kv));
+            hook.on_activation(l, pos, Stage::Attention, &mut state.att);
+            profile::time(Op::MergeHeads, layer, || ld.merge_heads_to_resid_stream(state));
+            profile::time(Op::Ffn, layer, || ld.ffn(state));
+            // a skipped FFN leaves `h1` from an earlier layer
+            if !self.patch.skip_ffn.contains(&l) {
+                hook.on_activation(l, pos, Stage::FfnHidden, &mut state.h1);
+            }
+            hook.on_activation(l, pos, Stage::Residual, &mut state.x);
+        }
+
+        profile::time(Op::Classifier, None, || {
+            self.rms_final.inplace_rms_norm(&mut state.x);
+
+            match &self.wcls {
+                Some(w) => w.mat_vec(&state.x, &mut state.logits),
+                None => self.embeddings.mat_vec(&state.x, &mut state.logits),
+            }
+        });
+    }
+}
+
+impl<Lin, Rms, Emb, Buf> LlamaWeights<LayerWeights<Lin, Rms>, Rms, Emb, Buf> {
+    /// Run the forward pass with `patch`, in place of the previous one
+    pub fn set_patch(&mut self, patch: ModelPatch) {
+        for (l, layer) in self.layers.iter_mut().enumerate() {
+            layer.patch = patch.layer(l);
+        }
+        self.patch = patch;
+    }
+
+    pub fn patch(&self) -> &ModelPatch {
+        &self.patch
+    }
+}
+
+impl<L, Rms, Emb> LlamaWeights<L, Rms, Emb, Vec<Ty>>
+where
+    Rms: RMSNormWeight<Vec<Ty>>,
+    Emb: EmbeddingTable<Vec<Ty>>,
+{
+    /// Logits for a residual stream `x` taken anywhere in the model (final norm and classifier),
+    /// the logit lens
+    pub fn classify(&self, x: &[Ty], logits: &mut Vec<Ty>) {
+        let mut normed = x.to_vec();
+        self.rms_final.inplace_rms_norm(&mut normed);
+        match &self.wcls {
+            Some(w) => w.mat_vec(&normed, logits),
+            None => self.embeddings.mat_vec(&normed, logits),
+        }
+    }
+}
+
+// f32 Implementation of Llama2 layer
+impl<Lin, Rms> LLamaLayer<Vec<Ty>> for LayerWeights<Lin, Rms>
+where
+    Lin: LinearWeight<Vec<Ty>>,
+    Rms: RMSNormWeight<Vec<Ty>>,
+{
+    fn rms_and_qkv(&self, _cfg: &Config, state: &mut ExecutionState<Vec<Ty>>) {
+        self.rms_attn.rms_norm(&state.x, &mut state.xb);
+        self.wq.mat_vec(&state.xb, &mut state.q);
+        self.wk.mat_vec(&state.xb, &mut state.k);
+        self.wv.mat_vec(&state.xb, &mut state.v);
+    }
+    fn rope(
+        &self,
+        pos: usize,
+        cfg: &Config,
+        state: &mut ExecutionState<Vec<Ty>>,
+        rope_imag: &Vec<Ty>,
+        rope_real: &Vec<Ty>,
+    ) {
+        let head_size = cfg.dim / cfg.n_heads;
+
+        let q_heads = state.q.chunks_exact_mut(head_size);
+        let k_heads = state.k.chunks_exact_mut(head_size);
+
+        for (q, k) in q_heads.zip(k_heads) {
+            let mut re = rope_real[pos * head_size / 2..].iter().take(head_size / 2);
+
+            let mut im = rope_imag[pos * head_size / 2..].iter().take(head_size / 2);
+
+            for (qq, kk) in q.chunks_exact_mut(2).zip(k.chunks_exact_mut(2)) {
+                let (q0, q1) = (qq[0], qq[1]);
+                let (k0, k1) = (kk[0], kk[1]);
+                let fcr = re.next().unwrap();
+                let fci = im.next().unwrap();
+                qq[0] = q0 * fcr - q1 * fci;
+                qq[1] = q0 * fci + q1 * fcr;
+                kk[0] = k0 * fcr - k1 * fci;
+                kk[1] = k0 * fci + k1 * fcr;
+            }
+        }
+    }
+    fn cache_kv(
+        &self,
+        layer: usize,
+        pos: usize,
+        _cfg: &Config,
+        state: &ExecutionState<Vec<Ty>>,
+        kv: &mut PagedKV<Vec<Ty>>,
+    ) {
+        kv.write(layer, pos, &state.k, &state.v);
+    }
+
+    fn attention(
+        &self,
+        layer: usize,
+        pos: usize,
+        cfg: &Config,
+        state: &ExecutionState<Vec<Ty>>,
+        kv: &PagedKV<Vec<Ty>>,
+    ) {
+        // State is a shared reference becasue we will pass that to multiple threads.
+        // However we are going to take an unsafe mutable references inside the threads 
+        // We can do that because each thread handles a single head and head data is disjoint
+        let head_size = cfg.dim / cfg.n_heads;
+        let ablated_heads = &self.patch.ablated_heads;
+        let kv = kv.read();
+
+        let attn_lambda = |h: usize| {
+            let q = unsafe { _uncheked_slice(&state.q, h * head_size, head_size) };
+            // head attention weights of len (seq_len, )
+            let att_weights =
+                unsafe { _uncheked_mut_slice(&state.att, h * cfg.seq_len, cfg.seq_len) };
+            let xb = unsafe { _uncheked_mut_slice(&state.xb, h * head_size, head_size) };
+
+            // ablated heads add nothing to the residual stream
+            if ablated_heads.contains(&h) {
+                att_weights[..=pos].iter_mut().for_each(|a| *a = 0 as Ty);
+                xb.iter_mut().for_each(|v| *v = 0 as Ty);
+                return;
+            }
+
+            // do <Q,K> for head
+            for t in 0..=pos {
+                let score = kv.dot_k(layer, t, h, q);
+                let score = score / (head_size as Ty).sqrt();
+                unsafe {
+                    *att_weights.get_unchecked_mut(t) = score;
+                }
+            }
+
+            inplace_softmax(&mut att_weights[..=pos]);
+            // reset buffer head out buffer
+            xb.iter_mut().for_each(|v| *v = 0 as Ty);
+            // accumulate cached values to current buffer
+            // according to attention prob. (normalized weights)
+            for (t, &p_attn) in att_weights.iter().enumerate().take(pos + 1) {
+                kv.add_v(layer, t, h, p_attn, xb);
+            }
+        };
+
+        #[cfg(feature = "parallel")]
+        (0..cfg.n_heads).into_par_iter().for_each(attn_lambda);
+
+        #[cfg(not(feature = "parallel"))]
+        (0..cfg.n_heads).for_each(attn_lambda);
+    }
+
+    fn merge_heads_to_resid_stream(&self, state: &mut ExecutionState<Vec<Ty>>) {
+        // merge heads
+        // at this point result of all heads in in x[1],
+        // Linearly  merge all heads into a new buffer x[2]
+        self.wo.mat_vec(&state.xb, &mut state.xb2);
+
+        // add attention result to  residual stream
+        state
+            .x
+            .iter_mut()
+            .zip(state.xb2.iter())
+            .for_each(|(x, xb)| *x += *xb);
+    }
+
+    fn ffn(&self, state: &mut ExecutionState<Vec<Ty>>) {
+        if self.patch.skip_ffn {
+            return;
+        }
+        // normalize residual stream before FFN
+        self.rms_ffn.rms_norm(&state.x, &mut state.xb);
+
+        // FFN:
+        //  z = SiLU(W1 \dot x) * (W3 \dot x)
+        // out = (W2 \dot z)
+        self.w1.mat_vec(&state.xb, &mut state.h1);
+        self.w3.mat_vec(&state.xb, &mut state.h2);
+
+        // silu hidden
+        for h1 in state.h1.iter_mut() {
+            // 1 / 1 + exp(-hv)
+            let _scaler = (1 as Ty) / ((1 as Ty) + (-*h1).exp());
+            *h1 *= _scaler;
+        }
+
+        // combine hidden state with multiplication
+        for (h1, &h2) in state.h1.iter_mut().zip(state.h2.iter()) {
+            *h1 *= h2;
+        }
+        self.w2.mat_vec(&state.h1, &mut state.xb);
+
+        // add FFN result to residual stream
+        state
+            .x
+            .iter_mut()
+            .zip(state.xb.iter())
+            .for_each(|(x, z)| *x += *z);
+    }
+}
+
+/// Helper to simplifiy buffer init
+pub trait DefualtBuffer {
+    fn zeros(size: usize) -> Self;
+}
+
+impl DefualtBuffer for Vec<Ty> {
+    fn zeros(size: usize) -> Self {
+        vec![0 as Ty; size]
+    }
+}
+
+impl<T: DefualtBuffer> ExecutionState<T> {
+    fn init(cfg: &Config) -> Self {
+        Self {
+            x: T::zeros(cfg.dim),
+            xb: T::zeros(cfg.dim),
+            xb2: T::zeros(cfg.dim),
+            h1: T::zeros(cfg.hidden_dim),
+            h2: T::zeros(cfg.hidden_dim),
+            q: T::zeros(cfg.dim),
+            k: T::zeros(cfg.dim),
+            v: T::zeros(cfg.dim),
+            att: T::zeros(cfg.n_heads * cfg.seq_len),
+            logits: T::zeros(cfg.vocab_size),
+        }
+    }
+}
+
+impl EmbeddingTable<Vec<Ty>> for Vec<Ty> {
+    fn token_to_resid_stream(&self, pos: usize, dst: &mut Vec<Ty>, _cfg: &Config) {
+        let dim = dst.len();
+        self.chunks_exact(dim)
+            .skip(pos)
+            .take(1)
+            .for_each(|src| dst.as_mut_slice().copy_from_slice(src));
+    }
+}
+
+impl LinearWeight<Vec<Ty>> for Vec<Ty> {
+    fn mat_vec(&self, vec: &Vec<Ty>, dst: &mut Vec<Ty>) {
+        matmul(dst, vec, self); // in_dim is infered form x. need to remove from function sig
+    }
+}
+
+#[inline]
+fn _norm_const(vec: &[Ty]) -> Ty {
+    let dim = vec.len() as Ty;
+    let ssq = vec.iter().fold(0f32, |init, &v| init + v * v) / dim;
+    (1 as Ty) / (ssq + 1e-5).sqrt()
+}
+
+impl RMSNormWeight<Vec<Ty>> for Vec<Ty> {
+    fn rms_norm(&self, vec: &Vec<Ty>, out: &mut Vec<Ty>) {
+        let inv_denom = _norm_const(vec);
+
+        let w_it = self.iter();
+        let normed = vec.iter().zip(w_it).map(|(xx, ww)| xx * ww * inv_denom);
+        out.iter_mut().zip(normed).for_each(|(dst, src)| *dst = src);
+    }
+
+    fn inplace_rms_norm(&self, vec: &mut Vec<Ty>) {
+        let inv_denom = _norm_const(vec);
+
+        let w_it = self.iter();
+        vec.iter_mut()
+            .zip(w_it)
+            .for_each(|(dst, w)| (*dst) *= inv_denom * w);
+    }
+}
+
+fn _alloc_and_read(file: &mut File, size: usize) -> Vec<Ty> {
+    let bytes_to_read = size * std::mem::size_of::<Ty>();
+    let mut raw_w_data = vec![0; bytes_to_read];
+    file.read_exact(&mut raw_w_data)
+        .expect("Failed to read weights file");
+    unsafe {
+        let float_ptr = raw_w_data.as_ptr() as *const Ty;
+        let data = std::slice::from_raw_parts(float_ptr, size);
+        data.to_vec()
+    }
+}
+
+/// Load raw weights from Karphaty's models
+fn load_raw_karphaty(cfg: &Config, path: &str) -> ([Vec<Ty>; 13], Option<Vec<Ty>>) {
+    let mut model_bin = File::open(path).unwrap();
+
+    model_bin.seek(SeekFrom::Start(CONF_SIZE as u64)).unwrap();
+
+    let mut f = |s: usize| _alloc_and_read(&mut model_bin, s);
+    let head_size = cfg.dim / cfg.n_heads;
+    (
+        [
+            f(cfg.vocab_size * cfg.dim),
+            f(cfg.n_layers * cfg.dim),
+            f(cfg.n_layers * cfg.dim * cfg.dim),
+            f(cfg.n_layers * cfg.dim * cfg.dim),
+            f(cfg.n_layers * cfg.dim * cfg.dim),
+            f(cfg.n_layers * cfg.dim * cfg.dim),
+            f(cfg.n_layers * cfg.dim),
+            f(cfg.n_layers * cfg.dim * cfg.hidden_dim),
+            f(cfg.n_layers * cfg.dim * cfg.hidden_dim),
+            f(cfg.n_layers * cfg.dim * cfg.hidden_dim),
+            f(cfg.dim),
+            f(cfg.seq_len * (head_size / 2)),
+            f(cfg.seq_len * (head_size / 2)),
+        ],
+        cfg.shared_weights.then(|| f(cfg.vocab_size * cfg.dim)),
+    )
+}
+
+
+impl Llama2CPUFloat {
+
+    pub fn load_weights(cfg: &Config, path: &str) -> Self {
+        assert_eq!(
+            cfg.n_kv_heads, cfg.n_heads,
+            "Grouped query attention is not supported"
+        );
+        let (weights, wcls) = load_raw_karphaty(cfg, path);
+        let embeddings = weights[0].clone();
+
+        // Go over all layered weights, and make layer chunk out of them
+        let mut w_layer_iters = weights[1..10]
+            .iter()
+            .map(|v| {
+                let csize = v.len() / cfg.n_layers;
+                v.chunks(csize).map(|l| l.to_vec())
+            })
+            .collect::<Vec<_>>();
+
+        let layers = (0..cfg.n_layers)
+            .map(|_| LayerWeights::<Vec<Ty>, Vec<Ty>> {
+                rms_attn: w_layer_iters[0].next().unwrap(),
+                wq: w_layer_iters[1].next().unwrap(),
+                wk: w_layer_iters[2].next().unwrap(),
+                wv: w_layer_iters[3].next().unwrap(),
+                wo: w_layer_iters[4].next().unwrap(),
+                rms_ffn: w_layer_iters[5].next().unwrap(),
+                w1: w_layer_iters[6].next().unwrap(),
+                w2: w_layer_iters[7].next().unwrap(),
+                w3: w_layer_iters[8].next().unwrap(),
+                patch: LayerPatch::default(),
+            })
+            .collect();
+
+        let rms_final = weights[10].clone();
+        let rope_real = weights[11].clone();
+        let rope_imag = weights[12].clone();
+
+        Self {
+            embeddings,
+            layers,
+            rms_final,
+            rope_real,
+            rope_imag,
+            wcls,
+            patch: ModelPatch::default(),
+        }
+    }
+}
+
+
+
+impl Config {
+    /// Read raw bytes and force those to be our config type (which conforms to C mem layout)
+    pub fn from_file(path: &str) -> Self {
+        let mut model_bin = File::open(path)
+            .unwrap_or_else(|_| panic!("Couldn't find model file at {}", path));
+        let mut buffer = [0; CONF_SIZE];
+        model_bin.read_exact(&mut buffer).unwrap();
+        let raw_conf = unsafe { mem::transmute::<[u8; CONF_SIZE], [i32; CONF_VALS]>(buffer) };
+        let (vocab_size, shared_weights) = if raw_conf[5] < 0 {
+            (-raw_conf[5] as usize, true)
+        } else {
+            (raw_conf[5] as usize, false)
+        };
+
+        Self {
+            dim: raw_conf[0] as usize,
+            hidden_dim: raw_conf[1] as usize,
+            n_layers: raw_conf[2] as usize,
+            n_heads: raw_conf[3] as usize,
+            n_kv_heads: raw_conf[4] as usize,
+            vocab_size,
+            seq_len: raw_conf[6] as usize,
+            shared_weights,
+        }
+    }
+}
+
+/// Beginning of sequence token
+pub const BOS: usize = 1;
+/// End of sequence token
+pub const EOS: usize = 2;
+/// Raw byte `b` is encoded by token `b + BYTE_OFFSET`
+const BYTE_OFFSET: usize = 3;
+/// Every byte value, for byte token bytes
+static ALL_BYTES: [u8; 256] = {
+    let mut bytes = [0u8; 256];
+    let mut b = 0;
+    while b < 256 {
+        bytes[b] = b as u8;
+        b += 1;
+    }
+    bytes
+};
+
+pub struct Vocab {
+    bytes: Vec<u8>,
+    offsets: Vec<usize>,
+    /// token string -> token id (lowest id wins for duplicates), byte tokens left out
+    ids: HashMap<String, usize>,
+}
+
+impl Vocab {
+    pub fn from_file(vocab_size: usize, path: &str) -> Self {
+        let mut bytes = Vec::<u8>::new();
+        let mut offsets = vec![0usize; 1];
+        let mut vocab_bin = File::open(path)
+            .unwrap_or_else(|_| panic!("Couldn't find tokenizer file at {}", path));
+        let mut len = [0; 4];
+        let mut val = [0; 1];
+        for _ in 0..vocab_size {
+            vocab_bin.read_exact(&mut len).unwrap();
+            let l = i32::from_le_bytes(len);
+            offsets.push(offsets.last().unwrap() + l as usize);
+            (0..l).for_each(|_| {
+                vocab_bin.read_exact(&mut val).unwrap();
+                bytes.extend(val);
+            });
+        }
+
+        assert_eq!(offsets.len(), vocab_size + 1);
+
+        let mut vocab = Self {
+            bytes,
+            offsets,
+            ids: HashMap::new(),
+        };
+        // byte tokens are only a fallback for characters without a piece, e.g. `,` must be
+        // 29892 and not the byte token 47
+        for idx in (0..vocab_size).rev().filter(|&idx| !Self::is_byte_token(idx)) {
+            vocab.ids.insert(vocab.get_token(idx).to_string(), idx);
+        }
+        vocab
+    }
+
+    fn is_byte_token(idx: usize) -> bool {
+        (BYTE_OFFSET..BYTE_OFFSET + 256).contains(&idx)
+    }
+
+    /// Byte pair encode `text` (without BOS).
+    /// The tokenizer file has no merge scores, but pieces are sorted by merge priority,
+    /// so we always merge the pair that makes the lowest token id (the leftmost on ties).
+    pub fn encode(&self, text: &str) -> Vec<usize> {
+        // sentencepiece adds a dummy space prefix
+        let text = format!(" {}", text);
+        let mut tokens = Vec::new();
+        let mut buf = [0u8; 4];
+        for c in text.chars() {
+            let c = c.encode_utf8(&mut buf);
+            match self.ids.get(c as &str) {
+                Some(&id) => tokens.push(id),
+                None => tokens.extend(c.bytes().map(|b| b as usize + BYTE_OFFSET)),
+            }
+        }
+
+        let mut pair = String::new();
+        let mut merge = |a: usize, b: usize| {
+            if Self::is_byte_token(a) || Self::is_byte_token(b) {
+                return None;
+            }
+            pair.clear();
+            pair.push_str(self.get_token(a));
+            pair.push_str(self.get_token(b));
+            self.ids.get(pair.as_str()).copied()
+        };
+        // tokens are a linked list, candidate merges wait in a heap as
+        // (merged id, left, right, left token, right token)
+        let n = tokens.len();
+        let mut next = (1..=n).collect::<Vec<_>>();
+        let mut prev = (0..n).map(|i| i.checked_sub(1)).collect::<Vec<_>>();
+        let mut alive = vec![true; n];
+        let mut heap = BinaryHeap::new();
+        for i in 1..n {
+            if let Some(id) = merge(tokens[i - 1], tokens[i]) {
+                heap.push(Reverse((id, i - 1, i, tokens[i - 1], tokens[i])));
+            }
+        }
+        while let Some(Reverse((id, left, right, left_token, right_token))) = heap.pop() {
+            // either side changed since the pair was queued
+            if !alive[left] || !alive[right] || next[left] != right
+                || tokens[left] != left_token || tokens[right] != right_token
+            {
+                continue;
+            }
+            tokens[left] = id;
+            alive[right] = false;
+            next[left] = next[right];
+            if next[left] < n {
+                prev[next[left]] = Some(left);
+            }
+            // the merged token pairs up with its new neighbours
+            let neighbours = [prev[left].map(|p| (p, left)), Some((left, next[left]))];
+            for (a, b) in neighbours.into_iter().flatten().filter(|&(_, b)| b < n) {
+                if let Some(id) = merge(tokens[a], tokens[b]) {
+                    heap.push(Reverse((id, a, b, tokens[a], tokens[b])));
+                }
+            }
+        }
+        tokens
+            .into_iter()
+            .zip(alive)
+            .filter_map(|(t, alive)| alive.then_some(t))
+            .collect()
+    }
+
+    /// Raw bytes of a token. A byte token is its single byte, so tokens above 0x7f are not
+    /// valid UTF-8 on their own (the tokenizer file stores them as the char of that code point).
+    pub fn token_bytes(&self, idx: usize) -> &[u8] {
+        if Self::is_byte_token(idx) {
+            let b = idx - BYTE_OFFSET;
+            return &ALL_BYTES[b..b + 1];
+        }
+        &self.bytes[self.offsets[idx]..self.offsets[idx + 1]]
+    }
+
+    /// Text of a token, with bytes that are not valid UTF-8 on their own replaced
+    pub fn token_text(&self, idx: usize) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.token_bytes(idx))
+    }
+
+    pub fn get_token(&self, idx: usize) -> &str {
+        let (st, en) = (self.offsets[idx], self.offsets[idx + 1]);
+        let b = &self.bytes[st..en];
+        std::str::from_utf8(b).unwrap()
+    }
+}
+
+/// Wx: [n, d]x[d,] -> [n,]
+#[cfg(feature = "parallel")]
+fn matmul(out: &mut [Ty], x: &[Ty], w: &[Ty] ) {
+    let stride = x.len();
+    out.par_iter_mut().enumerate().for_each(|(i, out_val)| {
+        *out_val = unsafe {
+            _uncheked_slice(w, i * stride, stride)
+                .iter()
+                .zip(x.iter())
+                .fold(0 as Ty, |acc, (&_w, &_x)| acc + _w * _x)
+        };
+    });
+}
+
+#[cfg(not(feature = "parallel"))]
+fn matmul(out: &mut [Ty], x: &[Ty], w: &[Ty]) {
+    let stride = x.len();
+    for (row, out_elem) in w.chunks_exact(stride).zip(out.iter_mut()) {
+        let val = row
+            .iter()
+            .zip(x.iter())
+            .fold(0 as Ty, |acc, (&_w, &_x)| acc + _w * _x);
+        *out_elem = val;
+    }
+}
+
+/// We can safely borrow disjoint parts of slices, but its really hard for the borrow checker to know that this is safe
+#[allow(clippy::mut_from_ref)]
+unsafe fn _uncheked_mut_slice(s: &[Ty], offset: usize, size: usize) -> &mut [Ty] {
+    let ptr: *mut f32 = s.as_ptr() as *mut Ty;
+    let st = ptr.add(offset);
+    std::slice::from_raw_parts_mut(st, size)
+}
+
+/// We can safely borrow disjoint parts of slices, but its really hard for the borrow checker to know that this is safe
+unsafe fn _uncheked_slice<Q>(s: &[Q], offset: usize, size: usize) -> &[Q] {
+    let ptr = s.as_ptr();
+    let st = ptr.add(offset);
+    std::slice::from_raw_parts(st, size)
+}
+
+pub(crate) fn inplace_softmax(x: &mut [Ty]) {
+    let max_val = x.iter().fold(Ty::NAN, |acc, &v| v.max(acc));
+    let mut denom = 0 as Ty;
+    for v in x.iter_mut() {
+        *v = (*v - max_val).exp();
+        denom += *v;
+    }
+
+    x.iter_mut().for_each(|v| *v /= denom);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKENIZER: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../tokenizer.bin");
+
+    #[test]
+    fn encode_matches_llama_ids() {
+        let vocab = Vocab::from_file(32000, TOKENIZER);
+        // punctuation has its own pieces, the byte tokens are only a fallback
+        assert_eq!(vocab.encode("Hello, world."), [15043, 29892, 3186, 29889]);
+        // no piece for U+1F980, so its four UTF-8 bytes
+        assert_eq!(vocab.encode("🦀"), [29871, 243, 162, 169, 131]);
+    }
+
+    #[test]
+    fn byte_token_bytes() {
+        let vocab = Vocab::from_file(32000, TOKENIZER);
+        assert_eq!(vocab.token_bytes(b'\n' as usize + BYTE_OFFSET), b"\n");
+        assert_eq!(vocab.token_bytes(0x80 + BYTE_OFFSET), [0x80]);
+        let crab = vocab.encode("🦀")[1..]
+            .iter()
+            .flat_map(|&t| vocab.token_bytes(t))
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(crab, "🦀".as_bytes());
+        assert_eq!(vocab.token_bytes(15043), b" Hello");
+    }
+}
+
@@ -0,0 +1,344 @@
+//! LoRA adapters.
+//! An adapter adds `alpha / rank * B A` to some of the layer matrices, with A (rank, in_dim) and
+//! B (out_dim, rank). It is either merged into the weights at load time, or applied on the fly
+//! by [`LoraLinear`], which wraps the base [`LinearWeight`] so one base model serves several
+//! adapters.
+//!
+//! Adapter files follow the model files layout: a header of `n_layers`, `rank` and `targets`
+//! (i32, bit i set when the i-th of wq, wk, wv, wo, w1, w2, w3 is adapted) and `alpha` (f32),
+//! then for each adapted matrix in that order, A of all layers followed by B of all layers.
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::Read;
+
+use crate::{
+    _alloc_and_read, matmul, Config, LayerWeights, LinearWeight, Llama2CPUFloat, LlamaWeights, Ty,
+};
+
+pub const TARGETS: [&str; 7] = ["wq", "wk", "wv", "wo", "w1", "w2", "w3"];
+
+/// `(out_dim, in_dim)` of an adapted matrix
+fn target_shape(cfg: &Config, target: usize) -> (usize, usize) {
+    match TARGETS[target] {
+        "w1" | "w3" => (cfg.hidden_dim, cfg.dim),
+        "w2" => (cfg.dim, cfg.hidden_dim),
+        _ => (cfg.dim, cfg.dim),
+    }
+}
+
+/// Low rank update of one matrix of one layer
+#[derive(Debug, Clone)]
+pub struct LoraPair {
+    /// (rank, in_dim)
+    pub a: Vec<Ty>,
+    /// (out_dim, rank)
+    pub b: Vec<Ty>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LoraAdapter {
+    pub rank: usize,
+    pub alpha: Ty,
+    /// By target (in [`TARGETS`] order), then layer
+    pub pairs: [Option<Vec<LoraPair>>; 7],
+}
+
+impl LoraAdapter {
+    /// Read an adapter for a model of `cfg`, failing if its header doesn't fit the model
+    /// or the file is not the size the header implies
+    pub fn load(cfg: &Config, path: &str) -> Result<Self, String> {
+        let mut file = File::open(path)
+            .map_err(|e| format!("Couldn't open LoRA adapter at {}: {}", path, e))?;
+        let mut header = [0u8; 16];
+        file.read_exact(&mut header)
+            .map_err(|e| format!("Couldn't read LoRA adapter header: {}", e))?;
+        let int = |i: usize| i32::from_le_bytes(header[4 * i..4 * i + 4].try_into().unwrap());
+        let (n_layers, rank, targets) = (int(0), int(1), int(2));
+        let alpha = Ty::from_le_bytes(header[12..16].try_into().unwrap());
+        if usize::try_from(n_layers) != Ok(cfg.n_layers) {
+            return Err(format!(
+                "LoRA adapter has {} layers, the model {}",
+                n_layers, cfg.n_layers
+            ));
+        }
+        let n_layers = cfg.n_layers;
+        let rank = usize::try_from(rank)
+            .ok()
+            .filter(|&rank| rank > 0)
+            .ok_or_else(|| format!("LoRA rank must be positive, got {}", rank))?;
+        if targets as u32 >> TARGETS.len() != 0 {
+            return Err(format!("Unknown LoRA targets in {:#x}", targets));
+        }
+        let adapted = (0..TARGETS.len()).filter(|&target| targets & (1 << target) != 0);
+        let floats = adapted
+            .clone()
+            .map(|target| {
+                let (out_dim, in_dim) = target_shape(cfg, target);
+                n_layers * rank * (in_dim + out_dim)
+            })
+            .sum::<usize>();
+        let size = file.metadata().map_err(|e| e.to_string())?.len() as usize;
+        if size != header.len() + floats * std::mem::size_of::<Ty>() {
+            return Err(format!(
+                "LoRA adapter is {} bytes, its header ({} of rank {}) implies {}",
+                size,
+                adapted.map(|t| TARGETS[t]).collect::<Vec<_>>().join(", "),
+                rank,
+                header.len() + floats * std::mem::size_of::<Ty>()
+            ));
+        }
+
+        let pairs = std::array::from_fn(|target| {
+            if targets & (1 << target) == 0 {
+                return None;
+            }
+            let (out_dim, in_dim) = target_shape(cfg, target);
+            let a = _alloc_and_read(&mut file, n_layers * rank * in_dim);
+            let b = _alloc_and_read(&mut file, n_layers * out_dim * rank);
+            let pairs = a
+                .chunks_exact(rank * in_dim)
+                .zip(b.chunks_exact(out_dim * rank))
+                .map(|(a, b)| LoraPair {
+                    a: a.to_vec(),
+                    b: b.to_vec(),
+                })
+                .collect();
+            Some(pairs)
+        });
+        Ok(Self { rank, alpha, pairs })
+    }
+
+    pub fn scale(&self) -> Ty {
+        self.alpha / self.rank as Ty
+    }
+
+    fn pair(&self, target: usize, layer: usize) -> Option<&LoraPair> {
+        self.pairs[target].as_ref().map(|pairs| &pairs[layer])
+    }
+
+    /// Add the update to the weights, after which they run at full speed
+    pub fn merge_into(&self, weights: &mut Llama2CPUFloat) {
+        let scale = self.scale();
+        for (l, layer) in weights.layers.iter_mut().enumerate() {
+            let matrices = [
+                &mut layer.wq,
+                &mut layer.wk,
+                &mut layer.wv,
+                &mut layer.wo,
+                &mut layer.w1,
+                &mut layer.w2,
+                &mut layer.w3,
+            ];
+            for (target, w) in matrices.into_iter().enumerate() {
+                let Some(pair) = self.pair(target, l) else {
+                    continue;
+                };
+                let in_dim = pair.a.len() / self.rank;
+                // W += scale * B A, a row at a time
+                for (row, b) in w
+                    .chunks_exact_mut(in_dim)
+                    .zip(pair.b.chunks_exact(self.rank))
+                {
+                    for (&b, a) in b.iter().zip(pair.a.chunks_exact(in_dim)) {
+                        row.iter_mut()
+                            .zip(a)
+                            .for_each(|(w, &a)| *w += scale * b * a);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Weights running `base` with the update applied on the fly, `base` is borrowed as is
+    pub fn apply<'a>(&'a self, base: &'a Llama2CPUFloat) -> LoraCPUFloat<'a> {
+        let layers = base
+            .layers
+            .iter()
+            .enumerate()
+            .map(|(l, layer)| {
+                let wrap = |target: usize, base| LoraLinear {
+                    base,
+                    pair: self.pair(target, l),
+                    scale: self.scale(),
+                };
+                LayerWeights {
+                    rms_attn: &layer.rms_attn,
+                    rms_ffn: &layer.rms_ffn,
+                    wq: wrap(0, &layer.wq),
+                    wk: wrap(1, &layer.wk),
+                    wv: wrap(2, &layer.wv),
+                    wo: wrap(3, &layer.wo),
+                    w1: wrap(4, &layer.w1),
+                    w2: wrap(5, &layer.w2),
+                    w3: wrap(6, &layer.w3),
+                    patch: layer.patch.clone(),
+                }
+            })
+            .collect();
+        LlamaWeights {
+            embeddings: &base.embeddings,
+            layers,
+            rms_final: &base.rms_final,
+            rope_real: base.rope_real.clone(),
+            rope_imag: base.rope_imag.clone(),
+            wcls: base.wcls.as_ref(),
+            patch: base.patch.clone(),
+        }
+    }
+}
+
+thread_local! {
+    /// `x A^T` of [`LoraLinear::mat_vec`], so it doesn't allocate per call
+    static LOW: RefCell<Vec<Ty>> = const { RefCell::new(vec![]) };
+}
+
+/// A linear layer with its LoRA update, `x W^T + scale * x A^T B^T`
+pub struct LoraLinear<'a, L> {
+    pub base: L,
+    pub pair: Option<&'a LoraPair>,
+    pub scale: Ty,
+}
+
+impl<L: LinearWeight<Vec<Ty>>> LinearWeight<Vec<Ty>> for LoraLinear<'_, L> {
+    fn mat_vec(&self, vec: &Vec<Ty>, dst: &mut Vec<Ty>) {
+        self.base.mat_vec(vec, dst);
+        let Some(pair) = self.pair else {
+            return;
+        };
+        let rank = pair.b.len() / dst.len();
+        LOW.with_borrow_mut(|low| {
+            low.resize(rank, 0 as Ty);
+            matmul(low, vec, &pair.a);
+            low.iter_mut().for_each(|v| *v *= self.scale);
+            for (d, b) in dst.iter_mut().zip(pair.b.chunks_exact(rank)) {
+                *d += b.iter().zip(low.iter()).map(|(b, l)| b * l).sum::<Ty>();
+            }
+        });
+    }
+}
+
+/// Base weights borrowed, with an adapter applied on the fly
+pub type LoraCPUFloat<'a> = LlamaWeights<
+    LayerWeights<LoraLinear<'a, &'a Vec<Ty>>, &'a Vec<Ty>>,
+    &'a Vec<Ty>,
+    &'a Vec<Ty>,
+    Vec<Ty>,
+>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::tests::random_model;
+    use crate::kv::{blocks_for, BlockPool, KVFormat};
+    use crate::session::Session;
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+    use std::sync::{Arc, Mutex};
+
+    const CFG: Config = Config {
+        dim: 64,
+        hidden_dim: 172,
+        n_layers: 2,
+        n_heads: 4,
+        n_kv_heads: 4,
+        vocab_size: 256,
+        seq_len: 32,
+        shared_weights: false,
+    };
+
+    /// Adapter file bytes with the given header, and `floats` values after it
+    fn adapter_file(name: &str, header: [i32; 3], alpha: Ty, floats: usize) -> String {
+        let mut bytes = header
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>();
+        bytes.extend(alpha.to_le_bytes());
+        bytes.extend((0..floats).flat_map(|i| ((i % 7) as Ty * 0.01).to_le_bytes()));
+        let path = std::env::temp_dir().join(format!("lora-{}-{}.bin", std::process::id(), name));
+        std::fs::write(&path, bytes).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn load_checks_header() {
+        // wq and w2 of rank 4
+        let floats = 2 * 4 * (2 * CFG.dim + CFG.dim + CFG.hidden_dim);
+        let ok = adapter_file("ok", [2, 4, 0b100001], 8.0, floats);
+        let adapter = LoraAdapter::load(&CFG, &ok).unwrap();
+        assert_eq!((adapter.rank, adapter.scale()), (4, 2.0));
+        assert!(adapter.pairs[0].is_some() && adapter.pairs[5].is_some());
+        assert!(adapter.pairs[1].is_none());
+
+        let bad = [
+            ("layers", [3, 4, 0b100001], floats),
+            ("rank", [2, -4, 0b100001], floats),
+            ("targets", [2, 4, 0b10100001], floats),
+            ("size", [2, 4, 0b100001], floats - 1),
+        ];
+        for (name, header, floats) in bad {
+            let path = adapter_file(name, header, 8.0, floats);
+            assert!(LoraAdapter::load(&CFG, &path).is_err(), "{}", name);
+            std::fs::remove_file(path).unwrap();
+        }
+        std::fs::remove_file(ok).unwrap();
+    }
+
+    #[test]
+    fn merged_matches_runtime() {
+        let base = random_model(&CFG);
+        let mut rng = SmallRng::seed_from_u64(1);
+        let mut random = |n: usize| {
+            (0..n)
+                .map(|_| rng.gen_range(-0.1..0.1))
+                .collect::<Vec<Ty>>()
+        };
+        let rank = 4;
+        let pairs = std::array::from_fn(|target| {
+            // every other matrix
+            (target % 2 == 0).then(|| {
+                let (out_dim, in_dim) = target_shape(&CFG, target);
+                (0..CFG.n_layers)
+                    .map(|_| LoraPair {
+                        a: random(rank * in_dim),
+                        b: random(out_dim * rank),
+                    })
+                    .collect()
+            })
+        });
+        let adapter = LoraAdapter {
+            rank,
+            alpha: 8.0,
+            pairs,
+        };
+        let mut merged = random_model(&CFG);
+        adapter.merge_into(&mut merged);
+        let runtime = adapter.apply(&base);
+
+        let pool = |blocks| Arc::new(Mutex::new(BlockPool::new(&CFG, blocks, KVFormat::F32)));
+        let blocks = blocks_for(CFG.seq_len);
+        let (merged_pool, runtime_pool, base_pool) = (pool(blocks), pool(blocks), pool(blocks));
+        let mut merged_session = Session::new(&CFG, &merged_pool);
+        let mut runtime_session = Session::new(&CFG, &runtime_pool);
+        let mut base_session = Session::new(&CFG, &base_pool);
+        let (mut drift, mut change) = (0 as Ty, 0 as Ty);
+        for pos in 0..CFG.seq_len {
+            let token = (pos * 37) % CFG.vocab_size;
+            merged_session.feed(&merged, &CFG, token).unwrap();
+            runtime_session.feed(&runtime, &CFG, token).unwrap();
+            base_session.feed(&base, &CFG, token).unwrap();
+            let logits = merged_session.state.logits.iter();
+            let others = runtime_session
+                .state
+                .logits
+                .iter()
+                .zip(&base_session.state.logits);
+            for (m, (r, b)) in logits.zip(others) {
+                drift = drift.max((m - r).abs());
+                change = change.max((m - b).abs());
+            }
+        }
+        assert!(drift < 1e-4, "merged vs runtime logit drift: {:e}", drift);
+        // the adapter does something
+        assert!(change > 1e-2, "adapter logit change: {:e}", change);
+    }
+}
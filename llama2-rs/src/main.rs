@@ -1,674 +1,1051 @@
-use std::mem;
-use std::{
-    fs::File,
-    io::{Read, Seek, SeekFrom},
-};
-
-use rand::rngs::SmallRng;
-use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::fs;
 use std::io::{self, Write};
-
-#[cfg(feature = "parallel")]
-use rayon::prelude::*;
-
-const CONF_VALS: usize = 7;
-const CONF_SIZE: usize = std::mem::size_of::<[i32; CONF_VALS]>();
-
-#[derive(Debug, Clone, Copy)]
-pub struct Config {
-    dim: usize,
-    hidden_dim: usize,
-    n_layers: usize,
-    n_heads: usize,
-    n_kv_heads: usize,
-    vocab_size: usize,
-    seq_len: usize,
-    shared_weights: bool,
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use serde_json::json;
+
+use llama2_rs::batch;
+use llama2_rs::beam::{beam_search, BeamParams};
+use llama2_rs::bench::{self, BenchParams};
+use llama2_rs::chat::{ChatTemplate, Message};
+use llama2_rs::eval::{self, ContinuationScore};
+use llama2_rs::grammar::{Grammar, GrammarConstraint, TokenTrie};
+use llama2_rs::hooks::{write_npy, Capture, Stage};
+use llama2_rs::json_schema::grammar_from_schema;
+use llama2_rs::kv::{blocks_for, BlockPool, KVFormat};
+use llama2_rs::lens::logit_lens;
+use llama2_rs::lora::LoraAdapter;
+use llama2_rs::patch::ModelPatch;
+use llama2_rs::profile;
+use llama2_rs::regex::{Regex, RegexConstraint, RegexIndex};
+use llama2_rs::sampling::{logprobs_json, MaxLength, Penalties, SamplingParams, StopTokens};
+use llama2_rs::scheduler::BatchParams;
+use llama2_rs::server::Server;
+use llama2_rs::session::{PrefixCache, Session, POOL_EXHAUSTED};
+use llama2_rs::steering::{Steered, Steering};
+use llama2_rs::text::TextStream;
+use llama2_rs::{Config, LamaExecuter, Llama2CPUFloat, LlamaWeights, Ty, Vocab, BOS, EOS};
+
+/// Command line: `--name value` pairs are options, everything else is positional
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, String>,
 }
 
-/// Exexute LLama step
-pub trait LamaExecuter<Buffer> {
-    fn step(&mut self, token: usize, pos: usize, cfg: &Config, state: &mut ExecutionState<Buffer>);
-}
+impl Args {
+    fn from_env() -> Self {
+        let mut positional = vec![];
+        let mut options = HashMap::new();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some(name) => {
+                    let val = args
+                        .next()
+                        .unwrap_or_else(|| panic!("Missing value for --{}", name));
+                    options.insert(name.to_string(), val);
+                }
+                None => positional.push(arg),
+            }
+        }
+        Self {
+            positional,
+            options,
+        }
+    }
 
-/// Executte Llama layer
-pub trait LLamaLayer<Buffer> {
-    /// RMS norm residual stream and get Q,K,V matrices
-    fn rms_and_qkv(&self, cfg: &Config, state: &mut ExecutionState<Buffer>);
-    /// Rotate q and k heads according to position in seq (RoPE)
-    fn rope(
-        &self,
-        pos: usize,
-        cfg: &Config,
-        state: &mut ExecutionState<Buffer>,
-        rope_imag: &Buffer,
-        rope_real: &Buffer,
-    );
-    /// Cache sequence of Q, K (to be used for attention computation)
-    fn cache_kv(&mut self, pos: usize, cfg: &Config, state: &ExecutionState<Buffer>);
-    /// (per head) Calculate Attention weights, accumulate value according to weights
-    fn attention(&self, pos: usize, cfg: &Config, state: &ExecutionState<Buffer>);
-    /// Merge all heads and add result to residula stream
-    fn merge_heads_to_resid_stream(&self, state: &mut ExecutionState<Buffer>);
-    /// RMS norm residual stream,
-    /// apply FeedForward to normalized
-    /// add to residual stream
-    fn ffn(&self, state: &mut ExecutionState<Buffer>);
-}
+    /// Drop the first positional argument (the subcommand)
+    fn shift(mut self) -> Self {
+        self.positional.remove(0);
+        self
+    }
 
-pub trait LinearWeight<T> {
-    fn mat_vec(&self, vec: &T, dst: &mut T);
-}
-pub trait RMSNormWeight<T> {
-    fn rms_norm(&self, vec: &T, out: &mut T);
-    fn inplace_rms_norm(&self, vec: &mut T);
-}
+    fn nth(&self, n: usize) -> Option<String> {
+        self.positional.get(n).cloned()
+    }
 
-pub trait EmbeddingTable<Buf>: LinearWeight<Buf> {
-    fn token_to_resid_stream(&self, token: usize, dst: &mut Buf, cfg: &Config);
+    fn opt<T: std::str::FromStr>(&self, name: &str) -> Option<T>
+    where
+        T::Err: std::fmt::Display,
+    {
+        self.options.get(name).map(|v| {
+            v.parse::<T>()
+                .unwrap_or_else(|e| panic!("Bad value for --{}: {}", name, e))
+        })
+    }
 }
 
-struct LlamaWeights<Layer, Rms, Emb, Buf> {
-    /// (vocab_size, dim)
-    embeddings: Emb,
-    layers: Vec<Layer>,
-    /// (dim,)
-    rms_final: Rms,
-    /// (seq_len, head_size/2)
-    rope_real: Buf,
-    /// (seq_len, head_size/2)
-    rope_imag: Buf,
-    wcls: Option<Emb>,
+fn main() {
+    let args = Args::from_env();
+    match args.nth(0).as_deref() {
+        Some("perplexity") => perplexity(args.shift()),
+        Some("multiple-choice") => multiple_choice(args.shift()),
+        Some("serve") => serve(args.shift()),
+        Some("chat") => chat(args.shift()),
+        Some("batch") => batch(args.shift()),
+        Some("bench") => bench(args.shift()),
+        Some("activations") => activations(args.shift()),
+        Some("steer") => steer(args.shift()),
+        Some("logit-lens") => lens(args.shift()),
+        _ => generate(args),
+    }
 }
 
-struct LayerWeights<Lin, Rms, Buf> {
-    rms_attn: Rms,
-    rms_ffn: Rms,
-    wq: Lin,
-    wk: Lin,
-    wv: Lin,
-    wo: Lin,
-    w1: Lin,
-    w2: Lin,
-    w3: Lin,
-    /// (seq_len, dim)
-    k_cache: Buf,
-    /// (seq_len, dim)
-    v_cache: Buf,
-}
+fn init_threads(config: &Config) {
+    #[cfg(feature = "parallel")]
+    {
+        use num_cpus;
+        let cpus = num_cpus::get();
+        let active_cpus = (cpus).max(1).min(config.n_heads); // use 75% of available cores
+        println!("--> [Running Inference on {} CPUs]\n\n", active_cpus);
 
-type Ty = f32;
-type CPULayerFloat = LayerWeights<Vec<Ty>, Vec<Ty>, Vec<Ty>>;
-type Llama2CPUFloat = LlamaWeights<CPULayerFloat, Vec<Ty>, Vec<Ty>, Vec<Ty>>;
-
-pub struct ExecutionState<Buffer> {
-    /// Shape:(dim,)
-    x: Buffer,   
-    /// Shape:(dim,)
-    xb: Buffer, 
-    /// Shape:(dim,)
-    xb2: Buffer,
-    /// Shape:(hidden_dim,)
-    h1: Buffer,
-    /// Shape:(hidden_dim,)
-    h2: Buffer,
-    /// (dim,): Q, buffers
-    q: Buffer,
-    /// (dim,): K buffer
-    k: Buffer,
-    /// (dim,): V buffer 
-    v: Buffer,
-    /// (n_heads, seq_len): Attention Weight Buffer
-    att: Buffer,
-    /// Logits: (vocab_size, )
-    logits: Buffer,
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(active_cpus)
+            .build_global()
+            .unwrap();
+    }
+    #[cfg(not(feature = "parallel"))]
+    let _ = config;
 }
 
-// f32 CPU implementation of Llama2
-impl<L, Rms, Emb> LamaExecuter<Vec<Ty>> for LlamaWeights<L, Rms, Emb, Vec<Ty>>
-where
-    L: LLamaLayer<Vec<Ty>>,
-    Rms: RMSNormWeight<Vec<Ty>>,
-    Emb: EmbeddingTable<Vec<Ty>>,
-{
-    fn step(
-        &mut self,
-        token: usize,
-        pos: usize,
-        cfg: &Config,
-        state: &mut ExecutionState<Vec<Ty>>,
-    ) {
-        // copy token embedding to residual stream
-        self.embeddings
-            .token_to_resid_stream(token, &mut state.x, cfg);
-
-        for ld in self.layers.iter_mut() {
-            ld.rms_and_qkv(cfg, state);
-            ld.rope(pos, cfg, state, &self.rope_imag, &self.rope_real);
-            ld.cache_kv(pos, cfg, state);
-            ld.attention(pos, cfg, state);
-            ld.merge_heads_to_resid_stream(state);
-            ld.ffn(state);
-        }
-
-        self.rms_final.inplace_rms_norm(&mut state.x);
+fn load_weights(config: &Config, model_path: &str) -> Llama2CPUFloat {
+    let st = Instant::now();
+    let weights = LlamaWeights::load_weights(config, model_path);
+    println!(
+        "--> [Loaded weights in {} secs]\n\n",
+        st.elapsed().as_secs()
+    );
+    weights
+}
 
-        if self.wcls.is_none() {
-            self.embeddings.mat_vec(&state.x, &mut state.logits);
-        } else {
-            let w = self.wcls.as_ref().unwrap();
-            w.mat_vec(&state.x, &mut state.logits);
-        }
+/// `--profile true` times each forward pass operation, `--trace <file>` also keeps a Chrome trace
+fn start_profile(args: &Args) {
+    let trace = args.options.contains_key("trace");
+    if args.opt("profile").unwrap_or(false) || trace {
+        profile::enable(trace);
     }
 }
 
-// f32 Implementation of Llama2 layer
-impl<Lin, Rms> LLamaLayer<Vec<Ty>> for LayerWeights<Lin, Rms, Vec<Ty>>
-where
-    Lin: LinearWeight<Vec<Ty>>,
-    Rms: RMSNormWeight<Vec<Ty>>,
-{
-    fn rms_and_qkv(&self, cfg: &Config, state: &mut ExecutionState<Vec<Ty>>) {
-        self.rms_attn.rms_norm(&state.x, &mut state.xb);
-        self.wq.mat_vec(&state.xb, &mut state.q);
-        self.wk.mat_vec(&state.xb, &mut state.k);
-        self.wv.mat_vec(&state.xb, &mut state.v);
-    }
-    fn rope(
-        &self,
-        pos: usize,
-        cfg: &Config,
-        state: &mut ExecutionState<Vec<Ty>>,
-        rope_imag: &Vec<Ty>,
-        rope_real: &Vec<Ty>,
-    ) {
-        let head_size = cfg.dim / cfg.n_heads;
-
-        let q_heads = state.q.chunks_exact_mut(head_size);
-        let k_heads = state.k.chunks_exact_mut(head_size);
-
-        for (q, k) in q_heads.zip(k_heads) {
-            let mut re = rope_real[pos * head_size / 2..].iter().take(head_size / 2);
-
-            let mut im = rope_imag[pos * head_size / 2..].iter().take(head_size / 2);
-
-            for (qq, kk) in q.chunks_exact_mut(2).zip(k.chunks_exact_mut(2)) {
-                let (q0, q1) = (qq[0], qq[1]);
-                let (k0, k1) = (kk[0], kk[1]);
-                let fcr = re.next().unwrap();
-                let fci = im.next().unwrap();
-                qq[0] = q0 * fcr - q1 * fci;
-                qq[1] = q0 * fci + q1 * fcr;
-                kk[0] = k0 * fcr - k1 * fci;
-                kk[1] = k0 * fci + k1 * fcr;
-            }
-        }
-    }
-    fn cache_kv(&mut self, pos: usize, cfg: &Config, state: &ExecutionState<Vec<Ty>>) {
-        let dst_k = &mut self.k_cache[pos * cfg.dim..(pos + 1) * cfg.dim];
-        let dst_v = &mut self.v_cache[pos * cfg.dim..(pos + 1) * cfg.dim];
-        dst_k.copy_from_slice(&state.k);
-        dst_v.copy_from_slice(&state.v);
+/// Print the profile table and write the trace, if profiling
+fn finish_profile(args: &Args) {
+    if !args.opt("profile").unwrap_or(false) && !args.options.contains_key("trace") {
+        return;
     }
-
-    fn attention(&self, pos: usize, cfg: &Config, state: &ExecutionState<Vec<Ty>>) {
-        // State is a shared reference becasue we will pass that to multiple threads.
-        // However we are going to take an unsafe mutable references inside the threads 
-        // We can do that because each thread handles a single head and head data is disjoint
-        let head_size = cfg.dim / cfg.n_heads;
-        let k_cache = self.k_cache.as_slice();
-        let v_cache = self.v_cache.as_slice();
-
-        let attn_lambda = |h: usize| {
-            let q = unsafe { _uncheked_slice(&state.q, h * head_size, head_size) };
-            // head attention weights of len (seq_len, )
-            let att_weights =
-                unsafe { _uncheked_mut_slice(&state.att, h * cfg.seq_len, cfg.seq_len) };
-            let xb = unsafe { _uncheked_mut_slice(&state.xb, h * head_size, head_size) };
-            // head K cache of (seq_len,head_size)
-            let mut head_k_cache = k_cache.chunks_exact(head_size).skip(h).step_by(cfg.n_heads);
-
-            // do <Q,K> for head
-            for t in 0..=pos {
-                let k = head_k_cache.next().unwrap(); // head_size
-                let score = k
-                    .iter()
-                    .zip(q.iter())
-                    .fold(0 as Ty, |acc, (_k, _q)| acc + _k * _q);
-                let score = score / (head_size as Ty).sqrt();
-                unsafe {
-                    *att_weights.get_unchecked_mut(t) = score;
-                }
-            }
-
-            // head V cache of (seq_len, head_size)
-            let head_v_cache = v_cache.chunks_exact(head_size).skip(h).step_by(cfg.n_heads);
-            inplace_softmax(&mut att_weights[..=pos]);
-            // reset buffer head out buffer
-            xb.iter_mut().for_each(|v| *v = 0 as Ty);
-            // accumulate cached values to current buffer
-            // according to attention prob. (normalized weights)
-            for (vals, p_attn) in head_v_cache.zip(att_weights.iter()).take(pos + 1) {
-                vals.iter()
-                    .zip(xb.iter_mut())
-                    .for_each(|(v, dst)| *dst += v * p_attn)
-            }
-        };
-
-        #[cfg(feature = "parallel")]
-        (0..cfg.n_heads).into_par_iter().for_each(attn_lambda);
-
-        #[cfg(not(feature = "parallel"))]
-        (0..cfg.n_heads).for_each(attn_lambda);
+    profile::disable();
+    println!("\n{}", profile::table());
+    if let Some(path) = args.opt::<String>("trace") {
+        fs::write(&path, profile::chrome_trace().to_string())
+            .unwrap_or_else(|_| panic!("Couldn't write trace to {}", path));
+        println!("--> [Trace written to {}]", path);
     }
+}
 
-    fn merge_heads_to_resid_stream(&self, state: &mut ExecutionState<Vec<Ty>>) {
-        // merge heads
-        // at this point result of all heads in in x[1],
-        // Linearly  merge all heads into a new buffer x[2]
-        self.wo.mat_vec(&state.xb, &mut state.xb2);
-
-        // add attention result to  residual stream
-        state
-            .x
-            .iter_mut()
-            .zip(state.xb2.iter())
-            .for_each(|(x, xb)| *x += *xb);
+/// Weights with heads ablated (`--ablate-heads 0:1,2:3` as layer:head), layers skipped
+/// (`--skip-layers 3,4`) and FFNs dropped (`--skip-ffn 5`)
+fn patched(args: &Args, config: &Config, mut weights: Llama2CPUFloat) -> Llama2CPUFloat {
+    let layers = |name: &str| {
+        args.opt::<String>(name).map_or(Default::default(), |list| {
+            ModelPatch::parse_layers(&list).unwrap_or_else(|e| panic!("{}", e))
+        })
+    };
+    let mut patch = ModelPatch {
+        skip_layers: layers("skip-layers"),
+        skip_ffn: layers("skip-ffn"),
+        ..Default::default()
+    };
+    if let Some(heads) = args.opt::<String>("ablate-heads") {
+        patch
+            .parse_heads(&heads)
+            .unwrap_or_else(|e| panic!("{}", e));
     }
-
-    fn ffn(&self, state: &mut ExecutionState<Vec<Ty>>) {
-        // normalize residual stream before FFN
-        self.rms_ffn.rms_norm(&state.x, &mut state.xb);
-
-        // FFN:
-        //  z = SiLU(W1 \dot x) * (W3 \dot x)
-        // out = (W2 \dot z)
-        self.w1.mat_vec(&state.xb, &mut state.h1);
-        self.w3.mat_vec(&state.xb, &mut state.h2);
-
-        // silu hidden
-        for h1 in state.h1.iter_mut() {
-            // 1 / 1 + exp(-hv)
-            let _scaler = (1 as Ty) / ((1 as Ty) + (-*h1).exp());
-            *h1 = *h1 * _scaler;
-        }
-
-        // combine hidden state with multiplication
-        for (h1, &h2) in state.h1.iter_mut().zip(state.h2.iter()) {
-            *h1 *= h2;
-        }
-        self.w2.mat_vec(&state.h1, &mut state.xb);
-
-        // add FFN result to residual stream
-        state
-            .x
-            .iter_mut()
-            .zip(state.xb.iter())
-            .for_each(|(x, z)| *x += *z);
+    if !patch.is_empty() {
+        patch.validate(config).unwrap_or_else(|e| panic!("{}", e));
+        println!("--> [Patched forward pass: {:?}]", patch);
+        weights.set_patch(patch);
     }
+    weights
 }
 
-/// Helper to simplifiy buffer init
-pub trait DefualtBuffer {
-    fn zeros(size: usize) -> Self;
-}
-
-impl DefualtBuffer for Vec<Ty> {
-    fn zeros(size: usize) -> Self {
-        vec![0 as Ty; size]
-    }
+/// The `--lora` adapter, if any
+fn lora_adapter(args: &Args, config: &Config) -> Option<LoraAdapter> {
+    args.opt::<String>("lora")
+        .map(|path| LoraAdapter::load(config, &path).unwrap_or_else(|e| panic!("{}", e)))
 }
 
-impl<T: DefualtBuffer> ExecutionState<T> {
-    fn init(cfg: &Config) -> Self {
-        Self {
-            x: T::zeros(cfg.dim),
-            xb: T::zeros(cfg.dim),
-            xb2: T::zeros(cfg.dim),
-            h1: T::zeros(cfg.hidden_dim),
-            h2: T::zeros(cfg.hidden_dim),
-            q: T::zeros(cfg.dim),
-            k: T::zeros(cfg.dim),
-            v: T::zeros(cfg.dim),
-            att: T::zeros(cfg.n_heads * cfg.seq_len),
-            logits: T::zeros(cfg.vocab_size),
+/// Weights with `adapter` merged in, or applied at runtime with `--lora-runtime true`
+fn adapted<'a>(
+    args: &Args,
+    weights: &'a mut Llama2CPUFloat,
+    adapter: &'a Option<LoraAdapter>,
+) -> Box<dyn LamaExecuter<Vec<Ty>> + Sync + 'a> {
+    match adapter {
+        Some(adapter) if args.opt("lora-runtime").unwrap_or(false) => {
+            Box::new(adapter.apply(weights))
+        }
+        Some(adapter) => {
+            adapter.merge_into(weights);
+            Box::new(&*weights)
         }
+        None => Box::new(&*weights),
     }
 }
 
-impl EmbeddingTable<Vec<Ty>> for Vec<Ty> {
-    fn token_to_resid_stream(&self, pos: usize, dst: &mut Vec<Ty>, cfg: &Config) {
-        let dim = dst.len();
-        self.chunks_exact(dim)
-            .skip(pos)
-            .take(1)
-            .for_each(|src| dst.as_mut_slice().copy_from_slice(src));
-    }
+/// Weights with the `--steering` vectors (scaled by `--steering-scale`) added, if any.
+/// `patch` is the one the weights run with.
+fn steered<W>(args: &Args, config: &Config, patch: &ModelPatch, weights: W) -> Steered<W> {
+    let steering = args
+        .opt::<String>("steering")
+        .map_or_else(Steering::default, |path| {
+            let src = fs::read_to_string(&path)
+                .unwrap_or_else(|_| panic!("Couldn't read steering vectors at {}", path));
+            let scale = args.opt("steering-scale").unwrap_or(1 as Ty);
+            Steering::from_json(&src, config, scale).unwrap_or_else(|e| panic!("{}", e))
+        });
+    steering
+        .check_patch(patch)
+        .unwrap_or_else(|e| panic!("{}", e));
+    Steered { weights, steering }
 }
 
-impl LinearWeight<Vec<Ty>> for Vec<Ty> {
-    fn mat_vec(&self, vec: &Vec<Ty>, dst: &mut Vec<Ty>) {
-        matmul(dst, vec, &self); // in_dim is infered form x. need to remove from function sig
+/// Sampling knobs from `--top-k`, `--top-p`, the penalties and `--seed`
+fn sampling_params(args: &Args, temperature: Ty) -> SamplingParams {
+    let defaults = SamplingParams::default();
+    SamplingParams {
+        temperature,
+        top_k: args.opt("top-k").unwrap_or(defaults.top_k),
+        top_p: args.opt("top-p").unwrap_or(defaults.top_p),
+        penalties: Penalties {
+            repetition: args
+                .opt("repetition-penalty")
+                .unwrap_or(defaults.penalties.repetition),
+            frequency: args
+                .opt("frequency-penalty")
+                .unwrap_or(defaults.penalties.frequency),
+            presence: args
+                .opt("presence-penalty")
+                .unwrap_or(defaults.penalties.presence),
+            window: args
+                .opt("penalty-window")
+                .unwrap_or(defaults.penalties.window),
+        },
+        seed: args.opt("seed"),
     }
 }
 
-#[inline]
-fn _norm_const(vec: &[Ty]) -> Ty {
-    let dim = vec.len() as Ty;
-    let ssq = vec.iter().fold(0f32, |init, &v| init + v * v) / dim;
-    (1 as Ty) / (ssq + 1e-5).sqrt()
-}
+/// `<model> [temperature] [seq_len] [prompt]`: generate from a prompt
+fn generate(args: Args) {
+    let model_path = args.nth(0).expect("Must pass weights path");
+    let temperature = args.nth(1).map_or(0 as Ty, |v| {
+        v.parse::<Ty>().expect("temperature must be a float")
+    });
 
-impl RMSNormWeight<Vec<Ty>> for Vec<Ty> {
-    fn rms_norm(&self, vec: &Vec<Ty>, out: &mut Vec<Ty>) {
-        let inv_denom = _norm_const(vec);
+    let tokenizer_path = "tokenizer.bin";
 
-        let w_it = self.iter();
-        let normed = vec.iter().zip(w_it).map(|(xx, ww)| xx * ww * inv_denom);
-        out.iter_mut().zip(normed).for_each(|(dst, src)| *dst = src);
-    }
+    let config = Config::from_file(&model_path);
+    let seq_len = args.nth(2).map_or(config.seq_len, |v| {
+        v.parse::<usize>().expect("Sequence len must be integer")
+    });
+    let kv_format = args.opt("kv-cache").unwrap_or(KVFormat::F32);
+    let params = sampling_params(&args, temperature);
+
+    let logprobs = args.opt::<usize>("logprobs");
+    let beam = args.opt::<usize>("beams").map(|width| {
+        let defaults = BeamParams::default();
+        BeamParams {
+            width,
+            length_penalty: args
+                .opt("length-penalty")
+                .unwrap_or(defaults.length_penalty),
+            early_stopping: args
+                .opt("early-stopping")
+                .unwrap_or(defaults.early_stopping),
+            max_new_tokens: defaults.max_new_tokens,
+        }
+    });
 
-    fn inplace_rms_norm(&self, vec: &mut Vec<Ty>) {
-        let inv_denom = _norm_const(vec);
+    init_threads(&config);
 
-        let w_it = self.iter();
-        vec.iter_mut()
-            .zip(w_it)
-            .for_each(|(dst, w)| (*dst) *= inv_denom * w);
-    }
-}
+    let vocab = Vocab::from_file(config.vocab_size, tokenizer_path);
+    let grammar = args.opt::<String>("grammar").map(|path| {
+        let src = fs::read_to_string(&path)
+            .unwrap_or_else(|_| panic!("Couldn't read grammar file at {}", path));
+        Arc::new(Grammar::parse(&src).unwrap_or_else(|e| panic!("{}", e)))
+    });
+    let grammar = grammar.or_else(|| {
+        args.opt::<String>("json-schema").map(|path| {
+            let src = fs::read_to_string(&path)
+                .unwrap_or_else(|_| panic!("Couldn't read JSON schema at {}", path));
+            Arc::new(grammar_from_schema(&src).unwrap_or_else(|e| panic!("{}", e)))
+        })
+    });
+    let regex = args
+        .opt::<String>("regex")
+        .map(|pattern| Regex::new(&pattern).unwrap_or_else(|e| panic!("{}", e)));
+    let trie = (grammar.is_some() || regex.is_some()).then(|| Arc::new(TokenTrie::new(&vocab)));
+    let regex = regex.map(|regex| Arc::new(RegexIndex::new(regex, trie.clone().unwrap())));
+    let adapter = lora_adapter(&args, &config);
+    let mut weights = patched(&args, &config, load_weights(&config, &model_path));
+    let patch = weights.patch().clone();
+    let weights = steered(
+        &args,
+        &config,
+        &patch,
+        adapted(&args, &mut weights, &adapter),
+    );
 
-fn _alloc_and_read(file: &mut File, size: usize) -> Vec<Ty> {
-    let bytes_to_read = size * std::mem::size_of::<Ty>();
-    let mut raw_w_data = vec![0; bytes_to_read];
-    file.read_exact(&mut raw_w_data)
-        .expect("Failed to read weights file");
-    unsafe {
-        let float_ptr = raw_w_data.as_ptr() as *const Ty;
-        let data = std::slice::from_raw_parts(float_ptr, size);
-        data.to_vec()
+    // "-" reads one prompt per line from stdin, prompts sharing a prefix reuse its K, V
+    let prompts: Box<dyn Iterator<Item = String>> = match args.nth(3) {
+        Some(p) if p == "-" => Box::new(io::stdin().lines().map(|l| l.unwrap())),
+        Some(p) => Box::new(std::iter::once(p)),
+        None => Box::new(std::iter::once(String::new())),
+    };
+    // keep one full context worth of prompt tokens around, on top of the running sequence(s),
+    // beams also need room for their copy on write blocks
+    let cached_blocks = blocks_for(config.seq_len);
+    let sequences = beam.map_or(1, |b| b.width);
+    let pool = Arc::new(Mutex::new(BlockPool::new(
+        &config,
+        (sequences + 1) * cached_blocks + sequences,
+        kv_format,
+    )));
+    let mut prefix_cache = PrefixCache::new(cached_blocks);
+    start_profile(&args);
+
+    for prompt in prompts {
+        let mut tokens = vec![BOS];
+        if !prompt.is_empty() {
+            tokens.extend(vocab.encode(&prompt));
+        }
+        if tokens.len() >= config.seq_len {
+            println!(
+                "--> [Prompt is {} tokens, the context is {}, skipped]",
+                tokens.len(),
+                config.seq_len
+            );
+            continue;
+        }
+        let mut session = Session::new(&config, &pool);
+
+        let st = Instant::now();
+        let reused = prefix_cache
+            .prefill(&mut session, &weights, &config, &tokens)
+            .expect(POOL_EXHAUSTED);
+        if reused > 0 {
+            println!("--> [Reused {} cached prompt tokens]", reused);
+        }
+        print!("{}", prompt);
+        if let Some(beam) = &beam {
+            let beam = BeamParams {
+                max_new_tokens: seq_len.saturating_sub(tokens.len()),
+                ..*beam
+            };
+            let hypotheses = beam_search(session, &weights, &config, &beam);
+            let best = &hypotheses[0];
+            best.tokens
+                .iter()
+                .for_each(|&t| print!("{}", vocab.get_token(t)));
+            println!(
+                "\n--> [Beam score {:.3}, {:.3} secs]",
+                best.score,
+                st.elapsed().as_secs_f32()
+            );
+            continue;
+        }
+        let mut pipeline = params.pipeline().stop_when(MaxLength(seq_len));
+        if let (Some(grammar), Some(trie)) = (&grammar, &trie) {
+            pipeline = pipeline
+                .constrain(GrammarConstraint::new(grammar.clone(), trie.clone()))
+                .stop_when(StopTokens(vec![EOS]));
+        } else if let Some(regex) = &regex {
+            pipeline = pipeline
+                .constrain(RegexConstraint::new(regex.clone()))
+                .stop_when(StopTokens(vec![EOS]));
+        }
+        if let Some(top_n) = logprobs {
+            let generated = session
+                .generate_logprobs(&weights, &config, &mut pipeline, top_n, |_| {})
+                .expect(POOL_EXHAUSTED);
+            println!("\n{}", logprobs_json(&vocab, &generated));
+        } else {
+            session
+                .generate(&weights, &config, &mut pipeline, |next| {
+                    print!("{}", vocab.get_token(next));
+                    io::stdout().flush().unwrap();
+                })
+                .expect(POOL_EXHAUSTED);
+        }
+        let ts = (session.pos() - reused) as f32 / st.elapsed().as_secs_f32();
+        println!("\n{:.3} Tokens/Sec", ts);
     }
+    finish_profile(&args);
 }
 
-/// Load raw weights from Karphaty's models
-fn load_raw_karphaty(cfg: &Config, path: &str) -> ([Vec<Ty>; 13], Option<Vec<Ty>>) {
-    let mut model_bin = File::open(path).unwrap();
-
-    model_bin.seek(SeekFrom::Start(CONF_SIZE as u64)).unwrap();
-
-    let mut f = |s: usize| _alloc_and_read(&mut model_bin, s);
-    let head_size = cfg.dim / cfg.n_heads;
-    (
-        [
-            f(cfg.vocab_size * cfg.dim),
-            f(cfg.n_layers * cfg.dim),
-            f(cfg.n_layers * cfg.dim * cfg.dim),
-            f(cfg.n_layers * cfg.dim * cfg.dim),
-            f(cfg.n_layers * cfg.dim * cfg.dim),
-            f(cfg.n_layers * cfg.dim * cfg.dim),
-            f(cfg.n_layers * cfg.dim),
-            f(cfg.n_layers * cfg.dim * cfg.hidden_dim),
-            f(cfg.n_layers * cfg.dim * cfg.hidden_dim),
-            f(cfg.n_layers * cfg.dim * cfg.hidden_dim),
-            f(cfg.dim),
-            f(cfg.seq_len * (head_size / 2)),
-            f(cfg.seq_len * (head_size / 2)),
-        ],
-        cfg.shared_weights.then(|| f(cfg.vocab_size * cfg.dim)),
+/// `perplexity <model> <text file>`: average negative log likelihood and perplexity of a text
+fn perplexity(args: Args) {
+    let model_path = args.nth(0).expect("Must pass weights path");
+    let text_path = args.nth(1).expect("Must pass a text file");
+
+    let config = Config::from_file(&model_path);
+    let window = args
+        .opt("window")
+        .unwrap_or(config.seq_len)
+        .min(config.seq_len);
+    let stride = args.opt("stride").unwrap_or(window.saturating_sub(1));
+    let kv_format = args.opt("kv-cache").unwrap_or(KVFormat::F32);
+    init_threads(&config);
+
+    let vocab = Vocab::from_file(config.vocab_size, "tokenizer.bin");
+    let text = fs::read_to_string(&text_path)
+        .unwrap_or_else(|_| panic!("Couldn't read text file at {}", text_path));
+    let tokens = vocab.encode(&text);
+    let adapter = lora_adapter(&args, &config);
+    let mut weights = patched(&args, &config, load_weights(&config, &model_path));
+    let weights = adapted(&args, &mut weights, &adapter);
+    let pool = Arc::new(Mutex::new(BlockPool::new(
+        &config,
+        blocks_for(window),
+        kv_format,
+    )));
+
+    let st = Instant::now();
+    let mut windows = 0;
+    let result = eval::perplexity(
+        &weights,
+        &config,
+        &pool,
+        &tokens,
+        window,
+        stride,
+        |running| {
+            windows += 1;
+            println!(
+                "--> [Window {}, {}/{} tokens, perplexity {:.4}]",
+                windows,
+                running.tokens,
+                tokens.len(),
+                running.perplexity()
+            );
+        },
     )
+    .unwrap_or_else(|e| panic!("{}", e));
+    println!("Tokens: {}", result.tokens);
+    println!("NLL: {:.4}", result.mean_nll());
+    println!("Perplexity: {:.4}", result.perplexity());
+    let ts = result.processed as f32 / st.elapsed().as_secs_f32();
+    println!("{:.3} Tokens/Sec", ts);
 }
 
+/// `multiple-choice <model> <tasks.jsonl>`: accuracy on HellaSwag style tasks, one
+/// `{"context": "...", "endings": ["...", ...], "label": 0}` per line
+fn multiple_choice(args: Args) {
+    let model_path = args.nth(0).expect("Must pass weights path");
+    let tasks_path = args.nth(1).expect("Must pass a tasks file");
 
-impl Llama2CPUFloat {
-
-    fn load_weights(cfg: &Config, path: &str) -> Self {
-        let (weights, wcls) = load_raw_karphaty(cfg, path);
-        let embeddings = weights[0].clone();
+    let config = Config::from_file(&model_path);
+    let kv_format = args.opt("kv-cache").unwrap_or(KVFormat::F32);
+    init_threads(&config);
+
+    let vocab = Vocab::from_file(config.vocab_size, "tokenizer.bin");
+    let tasks = fs::read_to_string(&tasks_path)
+        .unwrap_or_else(|_| panic!("Couldn't read tasks file at {}", tasks_path));
+    let weights = patched(&args, &config, load_weights(&config, &model_path));
+    // the context, and one continuation at a time copying the last context block
+    let pool = Arc::new(Mutex::new(BlockPool::new(
+        &config,
+        2 * blocks_for(config.seq_len) + 1,
+        kv_format,
+    )));
 
-        // Go over all layered weights, and make layer chunk out of them
-        let mut w_layer_iters = weights[1..10]
+    let st = Instant::now();
+    let (mut total, mut correct, mut correct_norm, mut skipped) = (0, 0, 0, 0);
+    for (line, task) in tasks.lines().enumerate() {
+        if task.trim().is_empty() {
+            continue;
+        }
+        let task: serde_json::Value = serde_json::from_str(task)
+            .unwrap_or_else(|e| panic!("Bad task at line {}: {}", line + 1, e));
+        let context = task["context"]
+            .as_str()
+            .unwrap_or_else(|| panic!("Task at line {} has no context", line + 1));
+        let endings = task["endings"]
+            .as_array()
+            .and_then(|e| e.iter().map(|e| e.as_str()).collect::<Option<Vec<_>>>())
+            .unwrap_or_else(|| panic!("Task at line {} has no endings", line + 1));
+        let label = task["label"]
+            .as_u64()
+            .unwrap_or_else(|| panic!("Task at line {} has no label", line + 1))
+            as usize;
+
+        let mut context_tokens = vec![BOS];
+        context_tokens.extend(vocab.encode(context));
+        // the dummy space prefix of `encode` separates the ending from the context
+        let continuations = endings
             .iter()
-            .map(|v| {
-                let csize = v.len() / cfg.n_layers;
-                v.chunks(csize).map(|l| l.to_vec())
-            })
+            .map(|e| vocab.encode(e.trim_start()))
             .collect::<Vec<_>>();
+        // keep BOS and the end of a context that doesn't fit with the endings
+        let Some(context_tokens) =
+            eval::fit_context(&context_tokens, &continuations, config.seq_len)
+        else {
+            skipped += 1;
+            println!(
+                "--> [Task at line {} skipped, an ending is longer than the context]",
+                line + 1
+            );
+            continue;
+        };
+        let scores =
+            eval::score_continuations(&weights, &config, &pool, &context_tokens, &continuations);
 
-        let layers = (0..cfg.n_layers)
-            .map(|l| LayerWeights::<Vec<Ty>, Vec<Ty>, Vec<Ty>> {
-                rms_attn: w_layer_iters[0].next().unwrap(),
-                wq: w_layer_iters[1].next().unwrap(),
-                wk: w_layer_iters[2].next().unwrap(),
-                wv: w_layer_iters[3].next().unwrap(),
-                wo: w_layer_iters[4].next().unwrap(),
-                rms_ffn: w_layer_iters[5].next().unwrap(),
-                w1: w_layer_iters[6].next().unwrap(),
-                w2: w_layer_iters[7].next().unwrap(),
-                w3: w_layer_iters[8].next().unwrap(),
-                k_cache: vec![0 as Ty; cfg.seq_len * cfg.dim],
-                v_cache: vec![0 as Ty; cfg.seq_len * cfg.dim],
-            })
-            .collect();
+        let best = |key: fn(&ContinuationScore) -> f64| {
+            (0..scores.len())
+                .max_by(|&a, &b| key(&scores[a]).total_cmp(&key(&scores[b])))
+                .unwrap()
+        };
+        let (pred, pred_norm) = (best(|s| s.logprob), best(ContinuationScore::normalized));
+        total += 1;
+        correct += (pred == label) as usize;
+        correct_norm += (pred_norm == label) as usize;
+        println!(
+            "--> [Task {}: label {}, predicted {}, length normalized {}]",
+            total, label, pred, pred_norm
+        );
+    }
+    println!("Tasks: {}", total);
+    if skipped > 0 {
+        println!("Skipped: {}", skipped);
+    }
+    println!("Accuracy: {:.4}", correct as f64 / total.max(1) as f64);
+    println!(
+        "Accuracy (length normalized): {:.4}",
+        correct_norm as f64 / total.max(1) as f64
+    );
+    println!("{:.3} secs", st.elapsed().as_secs_f32());
+}
 
-        let rms_final = weights[10].clone();
-        let rope_real = weights[11].clone();
-        let rope_imag = weights[12].clone();
+/// `serve <model>`: OpenAI compatible completion server
+fn serve(args: Args) {
+    let model_path = args.nth(0).expect("Must pass weights path");
+    let host = args.opt::<String>("host").unwrap_or("127.0.0.1".into());
+    let port = args.opt::<u16>("port").unwrap_or(8080);
 
-        Self {
-            embeddings,
-            layers,
-            rms_final,
-            rope_real,
-            rope_imag,
-            wcls,
-        }
+    let config = Config::from_file(&model_path);
+    let kv_format = args.opt("kv-cache").unwrap_or(KVFormat::F32);
+    let template = chat_template(&args);
+    let mut batch = BatchParams::new(&config, args.opt("max-batch").unwrap_or(8));
+    if let Some(blocks) = args.opt("kv-blocks") {
+        batch.max_kv_blocks = blocks;
+    }
+    if let Some(chunk) = args.opt("prefill-chunk") {
+        batch.prefill_chunk = chunk;
     }
+    init_threads(&config);
+
+    let vocab = Vocab::from_file(config.vocab_size, "tokenizer.bin");
+    let weights = load_weights(&config, &model_path);
+    let model = std::path::Path::new(&model_path)
+        .file_stem()
+        .map_or(model_path.clone(), |s| s.to_string_lossy().into_owned());
+    let server = Arc::new(Server::new(
+        weights, config, vocab, kv_format, batch, template, &model,
+    ));
+    println!("--> [Serving {} on http://{}:{}/v1]", model, host, port);
+    server
+        .serve(&format!("{}:{}", host, port))
+        .unwrap_or_else(|e| panic!("Couldn't serve on {}:{}: {}", host, port, e));
 }
 
+/// `batch <model> <input.jsonl> <output.jsonl>`: generate for every record of a JSONL file.
+/// Records already in the output (by id) are skipped, so an interrupted run picks up where it
+/// stopped.
+fn batch(args: Args) {
+    let model_path = args.nth(0).expect("Must pass weights path");
+    let input_path = args.nth(1).expect("Must pass an input JSONL file");
+    let output_path = args.nth(2).expect("Must pass an output JSONL file");
 
+    let config = Config::from_file(&model_path);
+    let kv_format = args.opt("kv-cache").unwrap_or(KVFormat::F32);
+    let workers = args
+        .opt("workers")
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    init_threads(&config);
+
+    let done = batch::completed_ids(&output_path);
+    let input = fs::read_to_string(&input_path)
+        .unwrap_or_else(|_| panic!("Couldn't read records at {}", input_path));
+    let records = batch::pending(&input, &done);
+    println!(
+        "--> [{} records to run, {} already done, {} workers]",
+        records.len(),
+        done.len(),
+        workers
+    );
 
-impl Config {
-    /// Read raw bytes and force those to be our config type (which conforms to C mem layout)
-    fn from_file(path: &str) -> Self {
-        let mut model_bin =
-            File::open(path).expect(format!("Couldn't find model file at {}", path).as_str());
-        let mut buffer = [0; CONF_SIZE];
-        model_bin.read_exact(&mut buffer).unwrap();
-        let raw_conf = unsafe { mem::transmute::<[u8; CONF_SIZE], [i32; CONF_VALS]>(buffer) };
-        let (vocab_size, shared_weights) = if raw_conf[5] < 0 {
-            (-raw_conf[5] as usize, true)
-        } else {
-            (raw_conf[5] as usize, false)
-        };
+    let vocab = Vocab::from_file(config.vocab_size, "tokenizer.bin");
+    let weights = load_weights(&config, &model_path);
+    let mut output = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&output_path)
+        .unwrap_or_else(|_| panic!("Couldn't open {}", output_path));
 
-        Self {
-            dim: raw_conf[0] as usize,
-            hidden_dim: raw_conf[1] as usize,
-            n_layers: raw_conf[2] as usize,
-            n_heads: raw_conf[3] as usize,
-            n_kv_heads: raw_conf[4] as usize,
-            vocab_size,
-            seq_len: raw_conf[6] as usize,
-            shared_weights,
-        }
-    }
+    let st = Instant::now();
+    let total = records.len();
+    let mut finished = 0;
+    batch::run(
+        &weights,
+        &config,
+        &vocab,
+        kv_format,
+        records,
+        workers,
+        |result| {
+            // a line at a time, so an interrupted run leaves complete records behind
+            writeln!(output, "{}", result).unwrap();
+            output.flush().unwrap();
+            finished += 1;
+            print!("\r--> [{}/{}]", finished, total);
+            io::stdout().flush().unwrap();
+        },
+    );
+    println!("\n{:.3} secs", st.elapsed().as_secs_f32());
 }
 
-struct Vocab {
-    bytes: Vec<u8>,
-    offsets: Vec<usize>,
-}
+/// `bench <model>`: prefill and decode speed over `--iterations` runs (after `--warmup` ones) of a
+/// `--prompt-len` tokens prompt and `--gen-len` generated tokens. The summary is printed as JSON,
+/// and appended to `--output` if given.
+fn bench(args: Args) {
+    let model_path = args.nth(0).expect("Must pass weights path");
 
-impl Vocab {
-    fn from_file(vocab_size: usize, path: &str) -> Self {
-        let mut bytes = Vec::<u8>::new();
-        let mut offsets = vec![0usize; 1];
-        let mut vocab_bin =
-            File::open(path).expect(format!("Couldn't find tokenizer file at {}", path).as_str());
-        let mut len = [0; 4];
-        let mut val = [0; 1];
-        for vs in 0..vocab_size {
-            vocab_bin.read_exact(&mut len).unwrap();
-            let l = unsafe { mem::transmute::<[u8; 4], i32>(len) };
-            offsets.push(offsets.last().unwrap() + l as usize);
-            (0..l).for_each(|_| {
-                vocab_bin.read_exact(&mut val).unwrap();
-                bytes.extend(val);
-            });
-        }
+    let config = Config::from_file(&model_path);
+    let kv_format = args.opt("kv-cache").unwrap_or(KVFormat::F32);
+    let prompt_len = args
+        .opt("prompt-len")
+        .unwrap_or(config.seq_len.min(256) / 2);
+    let params = BenchParams {
+        gen_len: args.opt("gen-len").unwrap_or(config.seq_len.min(256) / 2),
+        warmup: args.opt("warmup").unwrap_or(1),
+        iterations: args.opt("iterations").unwrap_or(5),
+    };
+    init_threads(&config);
+
+    // story text rather than random tokens, the content doesn't change the speed much anyway
+    let vocab = Vocab::from_file(config.vocab_size, "tokenizer.bin");
+    let text = vocab
+        .encode("Once upon a time, there was a little girl named Lily who loved to play outside.");
+    let mut prompt = vec![BOS];
+    prompt.extend(text.iter().cycle().take(prompt_len.saturating_sub(1)));
+    let weights = load_weights(&config, &model_path);
+    let pool = Arc::new(Mutex::new(BlockPool::new(
+        &config,
+        blocks_for(config.seq_len),
+        kv_format,
+    )));
+
+    start_profile(&args);
+    let report = bench::run(&weights, &config, &pool, &prompt, params, |idx, it| {
+        let decode_secs = it.token_secs.iter().sum::<f64>();
+        println!(
+            "--> [Iteration {}: prefill {:.3} secs, decode {:.3} secs]",
+            idx + 1,
+            it.prefill_secs,
+            decode_secs
+        );
+    });
+    println!("Prefill: {:.3} Tokens/Sec", report.prefill_tok_s());
+    println!("Decode: {:.3} Tokens/Sec", report.decode_tok_s());
+    println!("Time to first token: {:.4} secs", report.ttft_secs());
+    println!(
+        "Token latency: p50 {:.4} secs, p95 {:.4} secs",
+        report.token_latency(50.),
+        report.token_latency(95.)
+    );
+    finish_profile(&args);
+
+    let mut json = report.to_json();
+    json["model"] = json!(model_path);
+    json["kv_cache"] = json!(args.opt::<String>("kv-cache").unwrap_or("f32".into()));
+    json["parallel"] = json!(cfg!(feature = "parallel"));
+    println!("{}", json);
+    if let Some(path) = args.opt::<String>("output") {
+        let mut output = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap_or_else(|_| panic!("Couldn't open {}", path));
+        writeln!(output, "{}", json).unwrap();
+    }
+}
 
-        assert_eq!(offsets.len(), vocab_size + 1);
+/// `activations <model> <prompt> <out dir>`: dump the intermediate tensors of every prompt
+/// position to `.npy` files, one per layer and stage (`--stages residual,attention,ffn_hidden`,
+/// `--layers 0,3`, all by default). Each has the positions as first axis, attention is
+/// (positions, n_heads, positions).
+fn activations(args: Args) {
+    let model_path = args.nth(0).expect("Must pass weights path");
+    let prompt = args.nth(1).expect("Must pass a prompt");
+    let out_dir = args.nth(2).expect("Must pass an output directory");
 
-        Self { bytes, offsets }
+    let config = Config::from_file(&model_path);
+    let stages = args
+        .opt::<String>("stages")
+        .map_or(Stage::ALL.to_vec(), |names| {
+            names
+                .split(',')
+                .map(|name| {
+                    Stage::from_name(name.trim())
+                        .unwrap_or_else(|| panic!("Unknown stage {}", name))
+                })
+                .collect()
+        });
+    let layers = args.opt::<String>("layers").map_or(vec![], |layers| {
+        layers
+            .split(',')
+            .map(|l| l.trim().parse().expect("Layers must be integers"))
+            .collect::<Vec<usize>>()
+    });
+    init_threads(&config);
+
+    let vocab = Vocab::from_file(config.vocab_size, "tokenizer.bin");
+    let mut tokens = vec![BOS];
+    tokens.extend(vocab.encode(&prompt));
+    tokens.truncate(config.seq_len);
+    let weights = load_weights(&config, &model_path);
+    let pool = Arc::new(Mutex::new(BlockPool::new(
+        &config,
+        blocks_for(config.seq_len),
+        KVFormat::F32,
+    )));
+
+    let mut capture = Capture::new(&config, &stages, &layers);
+    let mut session = Session::new(&config, &pool);
+    for &token in &tokens {
+        session
+            .feed_hooked(&weights, &config, token, &mut capture)
+            .expect(POOL_EXHAUSTED);
     }
 
-    fn get_token(&self, idx: usize) -> &str {
-        let (st, en) = (self.offsets[idx], self.offsets[idx + 1]);
-        let b = &self.bytes[st..en];
-        std::str::from_utf8(b).unwrap()
+    fs::create_dir_all(&out_dir).unwrap_or_else(|_| panic!("Couldn't create {}", out_dir));
+    let n = tokens.len();
+    let mut by_tensor = HashMap::<_, Vec<_>>::new();
+    for a in &capture.activations {
+        let tensor = match a.stage {
+            // only the positions of the prompt
+            Stage::Attention => a
+                .tensor
+                .chunks_exact(config.seq_len)
+                .flat_map(|head| &head[..n])
+                .copied()
+                .collect(),
+            _ => a.tensor.clone(),
+        };
+        by_tensor
+            .entry((a.layer, a.stage))
+            .or_default()
+            .push(tensor);
     }
-}
-
-/// Wx: [n, d]x[d,] -> [n,]
-#[cfg(feature = "parallel")]
-fn matmul(out: &mut [Ty], x: &[Ty], w: &[Ty] ) {
-    let stride = x.len();
-    out.par_iter_mut().enumerate().for_each(|(i, out_val)| {
-        *out_val = unsafe {
-            _uncheked_slice(w, i * stride, stride)
-                .iter()
-                .zip(x.iter())
-                .fold(0 as Ty, |acc, (&_w, &_x)| acc + _w * _x)
+    for ((layer, stage), rows) in by_tensor {
+        let shape = match stage {
+            Stage::Attention => vec![n, config.n_heads, n],
+            _ => vec![n, rows[0].len()],
         };
-    });
-}
-
-#[cfg(not(feature = "parallel"))]
-fn matmul(out: &mut [Ty], x: &[Ty], w: &[Ty]) {
-    let stride = x.len();
-    for (row, out_elem) in w.chunks_exact(stride).zip(out.iter_mut()) {
-        let val = row
-            .iter()
-            .zip(x.iter())
-            .fold(0 as Ty, |acc, (&_w, &_x)| acc + _w * _x);
-        *out_elem = val;
+        let path = format!("{}/layer{:02}_{}.npy", out_dir, layer, stage.name());
+        write_npy(&path, &shape, &rows.concat())
+            .unwrap_or_else(|e| panic!("Couldn't write {}: {}", path, e));
     }
+    let pieces = tokens
+        .iter()
+        .map(|&t| vocab.get_token(t))
+        .collect::<Vec<_>>();
+    fs::write(
+        format!("{}/tokens.json", out_dir),
+        json!({ "ids": tokens, "tokens": pieces }).to_string(),
+    )
+    .unwrap_or_else(|_| panic!("Couldn't write tokens to {}", out_dir));
+    println!(
+        "--> [{} tokens, {} tensors written to {}]",
+        n,
+        capture.activations.len(),
+        out_dir
+    );
 }
 
-/// We can safely borrow disjoint parts of slices, but its really hard for the borrow checker to know that this is safe
-unsafe fn _uncheked_mut_slice(s: &[Ty], offset: usize, size: usize) -> &mut [Ty] {
-    let ptr: *mut f32 = s.as_ptr() as *mut Ty;
-    let st = ptr.add(offset);
-    std::slice::from_raw_parts_mut(st, size)
-}
-
-/// We can safely borrow disjoint parts of slices, but its really hard for the borrow checker to know that this is safe
-unsafe fn _uncheked_slice<Q>(s: &[Q], offset: usize, size: usize) -> &[Q] {
-    let ptr = s.as_ptr();
-    let st = ptr.add(offset);
-    std::slice::from_raw_parts(st, size)
-}
+/// `steer <model> <pairs.jsonl> <out.json>`: steering vectors from contrastive prompts, one
+/// `{"positive": "...", "negative": "..."}` per line, for `--layers` (the middle one by default)
+fn steer(args: Args) {
+    let model_path = args.nth(0).expect("Must pass weights path");
+    let pairs_path = args.nth(1).expect("Must pass a prompt pairs file");
+    let out_path = args.nth(2).expect("Must pass an output file");
 
-fn inplace_softmax(x: &mut [Ty]) {
-    let max_val = x.iter().fold(Ty::NAN, |acc, &v| v.max(acc));
-    let mut denom = 0 as Ty;
-    for v in x.iter_mut() {
-        *v = (*v - max_val).exp();
-        denom += *v;
+    let config = Config::from_file(&model_path);
+    let layers = args
+        .opt::<String>("layers")
+        .map_or(vec![config.n_layers / 2], |layers| {
+            layers
+                .split(',')
+                .map(|l| l.trim().parse().expect("Layers must be integers"))
+                .collect::<Vec<usize>>()
+        });
+    if let Some(layer) = layers.iter().find(|&&l| l >= config.n_layers) {
+        panic!("Layer {} out of {}", layer, config.n_layers);
     }
-
-    x.iter_mut().for_each(|v| *v /= denom);
+    init_threads(&config);
+
+    let vocab = Vocab::from_file(config.vocab_size, "tokenizer.bin");
+    let encode = |text: &str| {
+        let mut tokens = vec![BOS];
+        tokens.extend(vocab.encode(text));
+        tokens.truncate(config.seq_len);
+        tokens
+    };
+    let pairs = fs::read_to_string(&pairs_path)
+        .unwrap_or_else(|_| panic!("Couldn't read prompt pairs at {}", pairs_path))
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let pair: serde_json::Value = serde_json::from_str(line)
+                .unwrap_or_else(|e| panic!("Bad prompt pair {}: {}", line, e));
+            let text = |key: &str| {
+                pair[key]
+                    .as_str()
+                    .unwrap_or_else(|| panic!("Prompt pair without {}: {}", key, line))
+                    .to_string()
+            };
+            (encode(&text("positive")), encode(&text("negative")))
+        })
+        .collect::<Vec<_>>();
+    let weights = load_weights(&config, &model_path);
+    let pool = Arc::new(Mutex::new(BlockPool::new(
+        &config,
+        blocks_for(config.seq_len),
+        KVFormat::F32,
+    )));
+
+    let steering = Steering::from_pairs(&weights, &config, &pool, &pairs, &layers);
+    fs::write(&out_path, steering.to_json().to_string())
+        .unwrap_or_else(|_| panic!("Couldn't write {}", out_path));
+    for (layer, v) in &steering.vectors {
+        let norm = v.iter().map(|x| x * x).sum::<Ty>().sqrt();
+        println!("--> [Layer {}: norm {:.4}]", layer, norm);
+    }
+    println!("--> [{} pairs, written to {}]", pairs.len(), out_path);
 }
 
-fn cdf_sample(probs: &[Ty]) -> usize {
-    let mut small_rng = SmallRng::from_entropy();
+/// `logit-lens <model> <prompt>`: the next token each layer would predict at every prompt
+/// position, as a table of the top one, and the `--top-k` ones (default 5) to `--json <file>`
+fn lens(args: Args) {
+    let model_path = args.nth(0).expect("Must pass weights path");
+    let prompt = args.nth(1).expect("Must pass a prompt");
+    let top_k = args.opt("top-k").unwrap_or(5);
 
-    let r = small_rng.gen::<Ty>();
-    let mut cdf = 0 as Ty;
-    for (idx, p) in probs.iter().enumerate() {
-        cdf += *p;
-        if r < cdf {
-            return idx;
+    let config = Config::from_file(&model_path);
+    init_threads(&config);
+
+    let vocab = Vocab::from_file(config.vocab_size, "tokenizer.bin");
+    let mut tokens = vec![BOS];
+    tokens.extend(vocab.encode(&prompt));
+    tokens.truncate(config.seq_len);
+    let weights = load_weights(&config, &model_path);
+    let pool = Arc::new(Mutex::new(BlockPool::new(
+        &config,
+        blocks_for(config.seq_len),
+        KVFormat::F32,
+    )));
+    let positions = logit_lens(&weights, &config, &pool, &tokens, top_k.max(1));
+
+    // pieces escaped and cut to fit a column
+    let piece = |token: usize| {
+        let piece = vocab.token_text(token).escape_debug().to_string();
+        piece.chars().take(10).collect::<String>()
+    };
+    print!("{:<12}", "token");
+    (0..config.n_layers).for_each(|l| print!(" {:<16}", format!("layer {}", l)));
+    println!();
+    for position in &positions {
+        print!("{:<12}", piece(position.token));
+        for top in &position.layers {
+            let (token, logprob) = top[0];
+            print!(" {:<16}", format!("{} {:.2}", piece(token), logprob.exp()));
         }
+        println!();
     }
-    probs.len() - 1
-}
 
-fn main() {
-    use std::env;
-    use std::time::Instant;
+    if let Some(path) = args.opt::<String>("json") {
+        let entry = |&(token, logprob): &(usize, Ty)| {
+            json!({
+                "id": token,
+                "token": vocab.token_text(token),
+                "bytes": vocab.token_bytes(token),
+                "logprob": logprob,
+            })
+        };
+        let positions = positions
+            .iter()
+            .enumerate()
+            .map(|(pos, p)| {
+                let layers = p
+                    .layers
+                    .iter()
+                    .map(|top| top.iter().map(entry).collect::<Vec<_>>())
+                    .collect::<Vec<_>>();
+                json!({
+                    "pos": pos,
+                    "id": p.token,
+                    "token": vocab.token_text(p.token),
+                    "bytes": vocab.token_bytes(p.token),
+                    "layers": layers,
+                })
+            })
+            .collect::<Vec<_>>();
+        fs::write(&path, json!({ "positions": positions }).to_string())
+            .unwrap_or_else(|_| panic!("Couldn't write {}", path));
+        println!("--> [Written to {}]", path);
+    }
+}
 
-    let model_path = env::args().nth(1).expect("Must pass weights path");
-    let temperature = env::args().nth(2).map_or(0 as Ty, |v| {
-        v.parse::<Ty>().expect("temperature must be a float")
-    });
+/// `--chat-template`: a built in template or a JSON file, Llama 2 by default
+fn chat_template(args: &Args) -> ChatTemplate {
+    let Some(name) = args.opt::<String>("chat-template") else {
+        return ChatTemplate::llama2();
+    };
+    ChatTemplate::named(&name).unwrap_or_else(|| {
+        let src = fs::read_to_string(&name)
+            .unwrap_or_else(|_| panic!("Couldn't read chat template at {}", name));
+        ChatTemplate::from_json(&src).unwrap_or_else(|e| panic!("{}", e))
+    })
+}
 
-    let tokenizer_path = "tokenizer.bin";
+/// `chat <model>`: talk to a chat model, one user message per line of stdin.
+/// The conversation stays in the K, V cache, each turn only runs its new tokens.
+fn chat(args: Args) {
+    let model_path = args.nth(0).expect("Must pass weights path");
+    let system = args.opt::<String>("system");
 
     let config = Config::from_file(&model_path);
-    let seq_len = env::args().nth(3).map_or(config.seq_len, |v| {
-        v.parse::<usize>().expect("Sequence len must be integer")
-    });
-
-    #[cfg(feature = "parallel")]
-    {
-        use num_cpus;
-        let cpus = num_cpus::get();
-        let active_cpus = (cpus).max(1).min(config.n_heads); // use 75% of available cores
-        println!("--> [Running Inference on {} CPUs]\n\n", active_cpus);
-
-        rayon::ThreadPoolBuilder::new()
-            .num_threads(active_cpus)
-            .build_global()
-            .unwrap();
-    }
-
-    let vocab = Vocab::from_file(config.vocab_size, tokenizer_path);
-    let st = Instant::now();
-    let mut weights = LlamaWeights::load_weights(&config, &model_path);
-    println!(
-        "--> [Loaded weights in {} secs]\n\n",
-        st.elapsed().as_secs()
-    );
+    let kv_format = args.opt("kv-cache").unwrap_or(KVFormat::F32);
+    let params = sampling_params(&args, args.opt("temperature").unwrap_or(0 as Ty));
+    let template = chat_template(&args);
+    init_threads(&config);
+
+    let vocab = Vocab::from_file(config.vocab_size, "tokenizer.bin");
+    let weights = load_weights(&config, &model_path);
+    let weights = steered(&args, &config, &ModelPatch::default(), weights);
+    let pool = Arc::new(Mutex::new(BlockPool::new(
+        &config,
+        blocks_for(config.seq_len),
+        kv_format,
+    )));
+    // answers get at least this much of the context, older turns are dropped to make room
+    let reply_room = config.seq_len / 4;
+
+    let mut session = Session::new(&config, &pool);
+    let mut history: Vec<Message> = system.iter().map(|s| Message::new("system", s)).collect();
+    print!("> ");
+    io::stdout().flush().unwrap();
+    for line in io::stdin().lines() {
+        let line = line.unwrap();
+        let user = line.trim();
+        if user.is_empty() {
+            print!("> ");
+            io::stdout().flush().unwrap();
+            continue;
+        }
 
-    let mut benches = vec![];
-    for _ in 0..1 {
-        let mut state = ExecutionState::<Vec<Ty>>::init(&config);
-        let mut probs = vec![0 as Ty; config.vocab_size];
+        let mut messages = history.clone();
+        messages.push(Message::new("user", user));
+        let fitted = fit_chat(&template, &vocab, &messages, config.seq_len, reply_room);
+        let Some((kept, prompt)) = fitted else {
+            println!("--> [Message doesn't fit in the context]\n");
+            print!("> ");
+            io::stdout().flush().unwrap();
+            continue;
+        };
+        if kept.len() < messages.len() {
+            let dropped = (messages.len() - kept.len()) / 2;
+            println!(
+                "--> [Context full, dropped the {} oldest exchanges]",
+                dropped
+            );
+        }
+        history = kept;
+
+        // the cache holds the conversation so far, up to where the answer tokens differ from
+        // the template rendering. The last prompt token always runs, its logits start the answer.
+        let cached = session.tokens().iter().zip(&prompt);
+        let common = cached.take_while(|(a, b)| a == b).count();
+        session.truncate(common.min(prompt.len() - 1));
+        for &token in &prompt[session.pos()..] {
+            session
+                .feed(&weights, &config, token)
+                .expect(POOL_EXHAUSTED);
+        }
 
-        let st = Instant::now();
-        let mut pos = 0;
-        let mut token = 1;
-        while pos < seq_len {
-            weights.step(token, pos, &config, &mut state);
-            //         state.step(token, pos, &weights, &config);
-            let next = if temperature == 0 as Ty {
-                state
-                    .logits
-                    .iter()
-                    .enumerate()
-                    .max_by(|(_, a), (_, b)| a.total_cmp(b))
-                    .map(|(index, _)| index)
-                    .unwrap()
-            } else {
-                state
-                    .logits
-                    .iter()
-                    .zip(probs.iter_mut())
-                    .for_each(|(logit, p)| *p = logit / temperature);
-                inplace_softmax(&mut probs);
-                cdf_sample(&probs)
-            };
-            print!("{}", vocab.get_token(next));
+        let mut pipeline = params.pipeline();
+        let mut text = TextStream::new(template.stop.clone());
+        let mut started = false;
+        let mut show = |chunk: &str| {
+            // answers tend to start with a space or a new line
+            let chunk = if started { chunk } else { chunk.trim_start() };
+            started |= !chunk.is_empty();
+            print!("{}", chunk);
             io::stdout().flush().unwrap();
-            pos += 1;
-            token = next;
+        };
+        let mut cut = false;
+        loop {
+            let (tokens, logits) = session.logits_mut();
+            let next = pipeline.next_token(tokens, logits);
+            if next == EOS {
+                break;
+            }
+            let (chunk, stopped) = text.push(vocab.token_bytes(next));
+            show(&chunk);
+            if stopped {
+                break;
+            }
+            if session.pos() >= config.seq_len {
+                cut = true;
+                break;
+            }
+            session.feed(&weights, &config, next).expect(POOL_EXHAUSTED);
         }
-        let ts = pos as f32 / st.elapsed().as_secs_f32();
-        benches.push(ts);
+        show(&text.flush());
+        if cut {
+            print!("\n--> [Answer cut at the end of the context]");
+        }
+        history.push(Message::new("assistant", text.text().trim()));
+        print!("\n\n> ");
+        io::stdout().flush().unwrap();
     }
-    let ts = benches.iter().fold(0f32, |acc, v| acc + v);
-    let ts = ts / (benches.len() as f32);
+    println!();
+}
 
-    println!("\n{:.3} Tokens/Sec", ts);
+/// Drop the oldest exchanges of `messages` until their prompt leaves `reply_room` in the
+/// context (or as much as possible). Returns the kept messages and their prompt,
+/// `None` if the last message alone doesn't fit.
+fn fit_chat(
+    template: &ChatTemplate,
+    vocab: &Vocab,
+    messages: &[Message],
+    seq_len: usize,
+    reply_room: usize,
+) -> Option<(Vec<Message>, Vec<usize>)> {
+    let system = messages.iter().take_while(|m| m.role == "system").count();
+    let mut fits = None;
+    for start in (system..messages.len()).step_by(2) {
+        let mut kept = messages[..system].to_vec();
+        kept.extend_from_slice(&messages[start..]);
+        let prompt = template
+            .render(vocab, &kept)
+            .unwrap_or_else(|e| panic!("{}", e));
+        if prompt.len() + reply_room <= seq_len {
+            return Some((kept, prompt));
+        }
+        if prompt.len() < seq_len {
+            fits = Some((kept, prompt));
+        }
+    }
+    // not much room for the answer
+    fits
 }
@@ -0,0 +1,165 @@
+//! Runtime changes to the forward pass, for pruning experiments: ablated attention heads, skipped
+//! layers and dropped FFNs. The weights themselves are left alone, see
+//! [`LlamaWeights::set_patch`](crate::LlamaWeights::set_patch).
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::Config;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModelPatch {
+    /// Heads whose attention output is zeroed, by layer
+    pub ablated_heads: BTreeMap<usize, BTreeSet<usize>>,
+    /// Layers left out entirely, the residual stream goes straight through
+    pub skip_layers: BTreeSet<usize>,
+    /// Layers that keep attention but drop their FFN
+    pub skip_ffn: BTreeSet<usize>,
+}
+
+/// What a single layer does differently
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LayerPatch {
+    pub ablated_heads: BTreeSet<usize>,
+    pub skip_ffn: bool,
+}
+
+impl ModelPatch {
+    pub fn is_empty(&self) -> bool {
+        self.ablated_heads.values().all(BTreeSet::is_empty)
+            && self.skip_layers.is_empty()
+            && self.skip_ffn.is_empty()
+    }
+
+    /// Ablate heads from a `layer:head,layer:head` list
+    pub fn parse_heads(&mut self, list: &str) -> Result<(), String> {
+        for entry in list.split(',').filter(|e| !e.trim().is_empty()) {
+            let (layer, head) = entry
+                .trim()
+                .split_once(':')
+                .and_then(|(l, h)| Some((l.parse().ok()?, h.parse().ok()?)))
+                .ok_or_else(|| format!("Bad head {}, expected layer:head", entry))?;
+            self.ablated_heads.entry(layer).or_default().insert(head);
+        }
+        Ok(())
+    }
+
+    /// Layers from a `3,4` list
+    pub fn parse_layers(list: &str) -> Result<BTreeSet<usize>, String> {
+        list.split(',')
+            .filter(|e| !e.trim().is_empty())
+            .map(|l| l.trim().parse().map_err(|_| format!("Bad layer {}", l)))
+            .collect()
+    }
+
+    /// Check layers and heads exist in the model
+    pub fn validate(&self, cfg: &Config) -> Result<(), String> {
+        let layers = self
+            .ablated_heads
+            .keys()
+            .chain(&self.skip_layers)
+            .chain(&self.skip_ffn);
+        if let Some(layer) = layers.into_iter().find(|&&l| l >= cfg.n_layers) {
+            return Err(format!("Layer {} out of {}", layer, cfg.n_layers));
+        }
+        let heads = self.ablated_heads.values().flatten();
+        if let Some(head) = heads.into_iter().find(|&&h| h >= cfg.n_heads) {
+            return Err(format!("Head {} out of {}", head, cfg.n_heads));
+        }
+        Ok(())
+    }
+
+    pub fn layer(&self, layer: usize) -> LayerPatch {
+        LayerPatch {
+            ablated_heads: self.ablated_heads.get(&layer).cloned().unwrap_or_default(),
+            skip_ffn: self.skip_ffn.contains(&layer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hooks::{Capture, Stage};
+    use crate::kv::tests::random_model;
+    use crate::kv::{blocks_for, BlockPool, KVFormat};
+    use crate::session::Session;
+    use crate::{LamaExecuter, Ty};
+    use std::sync::{Arc, Mutex};
+
+    const CFG: Config = Config {
+        dim: 64,
+        hidden_dim: 172,
+        n_layers: 2,
+        n_heads: 4,
+        n_kv_heads: 4,
+        vocab_size: 256,
+        seq_len: 16,
+        shared_weights: false,
+    };
+
+    /// Activations of `stages` and logits after every token of a fixed prompt
+    fn run<W: LamaExecuter<Vec<Ty>>>(weights: &W, stages: &[Stage]) -> (Capture, Vec<Vec<Ty>>) {
+        let pool = BlockPool::new(&CFG, blocks_for(CFG.seq_len), KVFormat::F32);
+        let mut session = Session::new(&CFG, &Arc::new(Mutex::new(pool)));
+        let mut capture = Capture::new(&CFG, stages, &[]);
+        let logits = (0..CFG.seq_len)
+            .map(|pos| {
+                let token = (pos * 37) % CFG.vocab_size;
+                session
+                    .feed_hooked(weights, &CFG, token, &mut capture)
+                    .unwrap();
+                session.state.logits.clone()
+            })
+            .collect();
+        (capture, logits)
+    }
+
+    #[test]
+    fn empty_patch_changes_nothing() {
+        let mut weights = random_model(&CFG);
+        let (_, base) = run(&weights, &[]);
+        weights.set_patch(ModelPatch {
+            skip_layers: [1].into(),
+            ..Default::default()
+        });
+        assert_ne!(run(&weights, &[]).1, base);
+        weights.set_patch(ModelPatch::default());
+        assert_eq!(run(&weights, &[]).1, base);
+    }
+
+    #[test]
+    fn ablating_all_heads_zeroes_attention() {
+        let mut weights = random_model(&CFG);
+        let mut patch = ModelPatch {
+            skip_ffn: [0].into(),
+            ..Default::default()
+        };
+        patch
+            .parse_heads(
+                &(0..CFG.n_heads)
+                    .map(|h| format!("0:{}", h))
+                    .collect::<Vec<_>>()
+                    .join(","),
+            )
+            .unwrap();
+        weights.set_patch(patch);
+        let (capture, _) = run(&weights, &[Stage::Attention, Stage::Residual]);
+        for a in capture.activations.iter().filter(|a| a.layer == 0) {
+            match a.stage {
+                Stage::Attention => assert!(a.tensor.iter().all(|&p| p == 0 as Ty)),
+                // nothing added to the token embedding
+                _ => {
+                    let token = (a.pos * 37) % CFG.vocab_size;
+                    let embedding = &weights.embeddings[token * CFG.dim..(token + 1) * CFG.dim];
+                    assert_eq!(a.tensor, embedding);
+                }
+            }
+        }
+        // layer 1 still attends
+        let layer1 = capture
+            .activations
+            .iter()
+            .find(|a| a.layer == 1 && a.stage == Stage::Attention)
+            .unwrap();
+        assert!(layer1.tensor.iter().any(|&p| p > 0 as Ty));
+    }
+}
@@ -0,0 +1,239 @@
+//! Per operation timing of the forward pass.
+//! Off by default, the check costs an atomic load per operation. Once [`enable`]d, every
+//! [`LLamaLayer`](crate::LLamaLayer) stage and the classifier are timed per layer, from any
+//! thread running the model, for an aggregated [`table`] and optionally a Chrome trace
+//! (load it in `chrome://tracing` or Perfetto).
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Op {
+    RmsAndQkv,
+    Rope,
+    CacheKv,
+    Attention,
+    MergeHeads,
+    Ffn,
+    /// Final norm and logits
+    Classifier,
+}
+
+impl Op {
+    pub const ALL: [Op; 7] = [
+        Op::RmsAndQkv,
+        Op::Rope,
+        Op::CacheKv,
+        Op::Attention,
+        Op::MergeHeads,
+        Op::Ffn,
+        Op::Classifier,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::RmsAndQkv => "rms_and_qkv",
+            Op::Rope => "rope",
+            Op::CacheKv => "cache_kv",
+            Op::Attention => "attention",
+            Op::MergeHeads => "merge_heads_to_resid_stream",
+            Op::Ffn => "ffn",
+            Op::Classifier => "classifier",
+        }
+    }
+}
+
+/// Calls and time of an operation
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stat {
+    pub calls: usize,
+    pub total: Duration,
+}
+
+/// One timed operation, for the trace
+#[derive(Debug, Clone, Copy)]
+pub struct Span {
+    pub op: Op,
+    pub layer: Option<usize>,
+    pub thread: usize,
+    /// Since the profiler was enabled
+    pub start: Duration,
+    pub duration: Duration,
+}
+
+#[derive(Default)]
+struct Profile {
+    /// By operation and layer (none for the classifier)
+    stats: BTreeMap<(Op, Option<usize>), Stat>,
+    spans: Vec<Span>,
+    trace: bool,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static PROFILE: Mutex<Option<Profile>> = Mutex::new(None);
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+thread_local! {
+    static THREAD: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Start timing operations, keeping every span too if `trace`. Clears what was recorded so far.
+pub fn enable(trace: bool) {
+    EPOCH.get_or_init(Instant::now);
+    *PROFILE.lock().unwrap() = Some(Profile {
+        trace,
+        ..Default::default()
+    });
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Forget what was recorded so far (e.g. warmup), keeps profiling if enabled
+pub fn reset() {
+    if let Some(profile) = PROFILE.lock().unwrap().as_mut() {
+        profile.stats.clear();
+        profile.spans.clear();
+    }
+}
+
+/// Run `f`, timing it as `op` of `layer` when profiling
+#[inline]
+pub(crate) fn time<R>(op: Op, layer: Option<usize>, f: impl FnOnce() -> R) -> R {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return f();
+    }
+    let st = Instant::now();
+    let out = f();
+    let duration = st.elapsed();
+
+    let mut profile = PROFILE.lock().unwrap();
+    let Some(profile) = profile.as_mut() else {
+        return out;
+    };
+    let stat = profile.stats.entry((op, layer)).or_default();
+    stat.calls += 1;
+    stat.total += duration;
+    if profile.trace {
+        profile.spans.push(Span {
+            op,
+            layer,
+            thread: thread_index(),
+            start: st.duration_since(*EPOCH.get().unwrap()),
+            duration,
+        });
+    }
+    out
+}
+
+/// Small stable id of the current thread, for the trace
+fn thread_index() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    THREAD.with(|t| {
+        t.get().unwrap_or_else(|| {
+            let idx = NEXT.fetch_add(1, Ordering::Relaxed);
+            t.set(Some(idx));
+            idx
+        })
+    })
+}
+
+/// Recorded stats by operation and layer
+pub fn stats() -> BTreeMap<(Op, Option<usize>), Stat> {
+    PROFILE
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map_or_else(BTreeMap::new, |p| p.stats.clone())
+}
+
+/// Aggregated timings: per operation over all layers, then per layer and operation (in ms)
+pub fn table() -> String {
+    let stats = stats();
+    let mut by_op = BTreeMap::<Op, Stat>::new();
+    let mut by_layer = BTreeMap::<usize, BTreeMap<Op, Duration>>::new();
+    for (&(op, layer), stat) in &stats {
+        let total = by_op.entry(op).or_default();
+        total.calls += stat.calls;
+        total.total += stat.total;
+        if let Some(layer) = layer {
+            *by_layer.entry(layer).or_default().entry(op).or_default() += stat.total;
+        }
+    }
+    let all = by_op.values().map(|s| s.total).sum::<Duration>();
+    let ms = |d: Duration| d.as_secs_f64() * 1e3;
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "{:<28} {:>10} {:>12} {:>12} {:>7}",
+        "op", "calls", "total ms", "mean us", "%"
+    )
+    .unwrap();
+    for (op, stat) in &by_op {
+        writeln!(
+            out,
+            "{:<28} {:>10} {:>12.3} {:>12.3} {:>7.2}",
+            op.name(),
+            stat.calls,
+            ms(stat.total),
+            stat.total.as_secs_f64() * 1e6 / stat.calls.max(1) as f64,
+            100. * stat.total.as_secs_f64() / all.as_secs_f64().max(f64::MIN_POSITIVE)
+        )
+        .unwrap();
+    }
+    writeln!(out, "{:<28} {:>10} {:>12.3}", "total", "", ms(all)).unwrap();
+
+    let layer_ops = &Op::ALL[..Op::ALL.len() - 1];
+    write!(out, "\n{:<6}", "layer").unwrap();
+    for op in layer_ops {
+        write!(out, " {:>12}", op.name().split('_').next().unwrap()).unwrap();
+    }
+    writeln!(out).unwrap();
+    for (layer, ops) in &by_layer {
+        write!(out, "{:<6}", layer).unwrap();
+        for op in layer_ops {
+            write!(
+                out,
+                " {:>12.3}",
+                ms(ops.get(op).copied().unwrap_or_default())
+            )
+            .unwrap();
+        }
+        writeln!(out).unwrap();
+    }
+    out
+}
+
+/// Recorded spans as Chrome trace events, empty unless enabled with `trace`
+pub fn chrome_trace() -> Value {
+    let profile = PROFILE.lock().unwrap();
+    let spans = profile.as_ref().map_or(&[][..], |p| &p.spans[..]);
+    let us = |d: Duration| d.as_secs_f64() * 1e6;
+    let events = spans
+        .iter()
+        .map(|span| {
+            let mut event = json!({
+                "name": span.op.name(),
+                "cat": "forward",
+                "ph": "X",
+                "ts": us(span.start),
+                "dur": us(span.duration),
+                "pid": 0,
+                "tid": span.thread,
+            });
+            if let Some(layer) = span.layer {
+                event["args"] = json!({ "layer": layer });
+            }
+            event
+        })
+        .collect::<Vec<_>>();
+    json!({ "traceEvents": events, "displayTimeUnit": "ms" })
+}
@@ -0,0 +1,607 @@
+//! Regex constrained decoding.
+//! The pattern is compiled to a DFA (regex -> Thompson NFA -> subset construction), then the
+//! tokens allowed from every DFA state are computed once up front, so masking a step is a lookup.
+//!
+//! Supported syntax: literals, `.`, char classes (`[a-z]`, `[^,]`), `\d` `\w` `\s` (ASCII) and
+//! their negations, groups (`(..)`, `(?:..)`), `|`, `*`, `+`, `?` and `{m}` `{m,}` `{m,n}`.
+//! The whole generated text has to match, `^` and `$` around the pattern are accepted and ignored.
+//! Token text is matched as is, including the leading space most words start with.
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+use crate::grammar::{mask_logits, TokenTrie};
+use crate::sampling::LogitsProcessor;
+use crate::{Ty, EOS};
+
+/// Sorted, disjoint, inclusive code point ranges
+type CharSet = Vec<(u32, u32)>;
+
+const MAX_CHAR: u32 = char::MAX as u32;
+/// Largest `n` accepted in `{m,n}`
+const MAX_REPEAT: usize = 1000;
+/// Patterns whose DFA would be larger are rejected
+const MAX_STATES: usize = 10_000;
+/// Patterns whose NFA would be larger are rejected before building it, nested counted repeats
+/// multiply
+const MAX_NFA_STATES: usize = 100_000;
+
+#[derive(Debug, Clone)]
+enum Node {
+    Empty,
+    Set(CharSet),
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Repeat(Box<Node>, usize, Option<usize>),
+}
+
+impl Node {
+    /// Number of NFA states [`Nfa::build`] adds for the node
+    fn nfa_states(&self) -> usize {
+        match self {
+            Node::Empty => 0,
+            Node::Set(_) => 1,
+            Node::Concat(seq) => seq
+                .iter()
+                .fold(0, |acc, n| acc.saturating_add(n.nfa_states())),
+            Node::Alt(alts) => alts.iter().fold(1, |acc, n| {
+                acc.saturating_add(n.nfa_states()).saturating_add(1)
+            }),
+            Node::Repeat(node, min, max) => {
+                let copies = max.unwrap_or(min + 1).max(1);
+                node.nfa_states().saturating_mul(copies).saturating_add(1)
+            }
+        }
+    }
+}
+
+fn normalize(mut set: CharSet) -> CharSet {
+    set.sort_unstable();
+    let mut out: CharSet = vec![];
+    for (lo, hi) in set {
+        match out.last_mut() {
+            Some(last) if lo <= last.1.saturating_add(1) => last.1 = last.1.max(hi),
+            _ => out.push((lo, hi)),
+        }
+    }
+    out
+}
+
+fn negate(set: &CharSet) -> CharSet {
+    let mut out = vec![];
+    let mut next = 0;
+    for &(lo, hi) in set {
+        if lo > next {
+            out.push((next, lo - 1));
+        }
+        next = hi + 1;
+    }
+    if next <= MAX_CHAR {
+        out.push((next, MAX_CHAR));
+    }
+    out
+}
+
+struct Parser {
+    src: Vec<char>,
+    at: usize,
+}
+
+impl Parser {
+    fn err<T>(&self, msg: &str) -> Result<T, String> {
+        Err(format!("Regex error at {}: {}", self.at, msg))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src.get(self.at).copied()
+    }
+
+    fn next(&mut self) -> Result<char, String> {
+        let c = self.peek();
+        self.at += 1;
+        c.map_or_else(|| self.err("Unexpected end of regex"), Ok)
+    }
+
+    fn parse_alternatives(&mut self) -> Result<Node, String> {
+        let mut alts = vec![self.parse_sequence()?];
+        while self.peek() == Some('|') {
+            self.at += 1;
+            alts.push(self.parse_sequence()?);
+        }
+        Ok(match alts.len() {
+            1 => alts.pop().unwrap(),
+            _ => Node::Alt(alts),
+        })
+    }
+
+    fn parse_sequence(&mut self) -> Result<Node, String> {
+        let mut seq = vec![];
+        loop {
+            let atom = match self.peek() {
+                None | Some('|') | Some(')') => break,
+                Some('(') => {
+                    self.at += 1;
+                    if self.src[self.at..].starts_with(&['?', ':']) {
+                        self.at += 2;
+                    }
+                    let node = self.parse_alternatives()?;
+                    if self.next()? != ')' {
+                        return self.err("Expected )");
+                    }
+                    node
+                }
+                Some('[') => {
+                    self.at += 1;
+                    Node::Set(self.parse_class()?)
+                }
+                Some('.') => {
+                    self.at += 1;
+                    Node::Set(negate(&vec![('\n' as u32, '\n' as u32)]))
+                }
+                Some('\\') => {
+                    self.at += 1;
+                    Node::Set(self.parse_escape()?)
+                }
+                Some('*') | Some('+') | Some('?') | Some('{') => {
+                    return self.err("Nothing to repeat")
+                }
+                Some(c) => {
+                    self.at += 1;
+                    Node::Set(vec![(c as u32, c as u32)])
+                }
+            };
+            seq.push(self.parse_repeats(atom)?);
+        }
+        Ok(match seq.len() {
+            0 => Node::Empty,
+            1 => seq.pop().unwrap(),
+            _ => Node::Concat(seq),
+        })
+    }
+
+    fn parse_repeats(&mut self, mut node: Node) -> Result<Node, String> {
+        loop {
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => {
+                    self.at += 1;
+                    let min = self.parse_count()?;
+                    let max = match self.peek() {
+                        Some(',') if self.src.get(self.at + 1) == Some(&'}') => {
+                            self.at += 1;
+                            None
+                        }
+                        Some(',') => {
+                            self.at += 1;
+                            Some(self.parse_count()?)
+                        }
+                        _ => Some(min),
+                    };
+                    if self.peek() != Some('}') {
+                        return self.err("Expected }");
+                    }
+                    if max.is_some_and(|max| max < min) {
+                        return self.err("Bad repetition bounds");
+                    }
+                    (min, max)
+                }
+                _ => return Ok(node),
+            };
+            self.at += 1;
+            node = Node::Repeat(Box::new(node), min, max);
+        }
+    }
+
+    fn parse_count(&mut self) -> Result<usize, String> {
+        let st = self.at;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.at += 1;
+        }
+        match self.src[st..self.at].iter().collect::<String>().parse() {
+            Ok(n) if n <= MAX_REPEAT => Ok(n),
+            Ok(_) => self.err(&format!("Repetitions are limited to {}", MAX_REPEAT)),
+            Err(_) => self.err("Expected a number"),
+        }
+    }
+
+    /// After the `\`
+    fn parse_escape(&mut self) -> Result<CharSet, String> {
+        let digit = vec![('0' as u32, '9' as u32)];
+        let word = normalize(vec![
+            ('0' as u32, '9' as u32),
+            ('A' as u32, 'Z' as u32),
+            ('_' as u32, '_' as u32),
+            ('a' as u32, 'z' as u32),
+        ]);
+        let space = normalize(
+            " \t\n\r\x0b\x0c"
+                .chars()
+                .map(|c| (c as u32, c as u32))
+                .collect(),
+        );
+        let c = match self.next()? {
+            'd' => return Ok(digit),
+            'D' => return Ok(negate(&digit)),
+            'w' => return Ok(word),
+            'W' => return Ok(negate(&word)),
+            's' => return Ok(space),
+            'S' => return Ok(negate(&space)),
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            c if c.is_ascii_alphanumeric() => return self.err(&format!("Unknown escape \\{}", c)),
+            c => c,
+        };
+        Ok(vec![(c as u32, c as u32)])
+    }
+
+    /// After the `[`
+    fn parse_class(&mut self) -> Result<CharSet, String> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.at += 1;
+        }
+        let mut set = vec![];
+        let mut first = true;
+        loop {
+            let lo = match self.next()? {
+                ']' if !first => break,
+                '\\' => {
+                    let esc = self.parse_escape()?;
+                    if esc.len() != 1 || esc[0].0 != esc[0].1 {
+                        set.extend(esc);
+                        first = false;
+                        continue;
+                    }
+                    esc[0].0
+                }
+                c => c as u32,
+            };
+            first = false;
+            let hi = if self.peek() == Some('-') && self.src.get(self.at + 1) != Some(&']') {
+                self.at += 1;
+                match self.next()? {
+                    '\\' => match self.parse_escape()?[..] {
+                        [(c, hi)] if c == hi => c,
+                        _ => return self.err("Bad class range"),
+                    },
+                    c => c as u32,
+                }
+            } else {
+                lo
+            };
+            if hi < lo {
+                return self.err("Bad class range");
+            }
+            set.push((lo, hi));
+        }
+        let set = normalize(set);
+        Ok(if negated { negate(&set) } else { set })
+    }
+}
+
+/// Thompson NFA, each state has epsilon edges and char set edges
+#[derive(Default)]
+struct Nfa {
+    eps: Vec<Vec<usize>>,
+    edges: Vec<Vec<(CharSet, usize)>>,
+}
+
+impl Nfa {
+    fn state(&mut self) -> usize {
+        self.eps.push(vec![]);
+        self.edges.push(vec![]);
+        self.eps.len() - 1
+    }
+
+    /// Add `node` starting at state `from`, returns the state it ends in
+    fn build(&mut self, node: &Node, from: usize) -> usize {
+        match node {
+            Node::Empty => from,
+            Node::Set(set) => {
+                let to = self.state();
+                self.edges[from].push((set.clone(), to));
+                to
+            }
+            Node::Concat(seq) => seq.iter().fold(from, |at, n| self.build(n, at)),
+            Node::Alt(alts) => {
+                let end = self.state();
+                for alt in alts {
+                    let st = self.state();
+                    self.eps[from].push(st);
+                    let e = self.build(alt, st);
+                    self.eps[e].push(end);
+                }
+                end
+            }
+            Node::Repeat(node, min, max) => {
+                let mut at = (0..*min).fold(from, |at, _| self.build(node, at));
+                match max {
+                    None => {
+                        let lp = self.state();
+                        self.eps[at].push(lp);
+                        let e = self.build(node, lp);
+                        self.eps[e].push(lp);
+                        lp
+                    }
+                    Some(max) => {
+                        let end = self.state();
+                        for _ in *min..*max {
+                            self.eps[at].push(end);
+                            at = self.build(node, at);
+                        }
+                        self.eps[at].push(end);
+                        end
+                    }
+                }
+            }
+        }
+    }
+
+    fn closure(&self, states: impl IntoIterator<Item = usize>) -> BTreeSet<usize> {
+        let mut set = BTreeSet::new();
+        let mut todo = states.into_iter().collect::<Vec<_>>();
+        while let Some(s) = todo.pop() {
+            if set.insert(s) {
+                todo.extend(&self.eps[s]);
+            }
+        }
+        set
+    }
+}
+
+/// Compiled regex
+#[derive(Debug, Clone)]
+pub struct Regex {
+    /// Per state, sorted `(lo, hi, next)` char ranges
+    transitions: Vec<Vec<(u32, u32, usize)>>,
+    accepting: Vec<bool>,
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        let pattern = pattern.strip_prefix('^').unwrap_or(pattern);
+        let pattern = match pattern.strip_suffix('$') {
+            Some(p) if !p.ends_with('\\') => p,
+            _ => pattern,
+        };
+        let mut parser = Parser {
+            src: pattern.chars().collect(),
+            at: 0,
+        };
+        let node = parser.parse_alternatives()?;
+        if parser.peek().is_some() {
+            return parser.err("Unmatched )");
+        }
+        if node.nfa_states() > MAX_NFA_STATES {
+            return Err(format!(
+                "Regex needs more than {} NFA states",
+                MAX_NFA_STATES
+            ));
+        }
+
+        let mut nfa = Nfa::default();
+        let start = nfa.state();
+        let end = nfa.build(&node, start);
+
+        // chars between consecutive boundaries behave the same everywhere in the NFA
+        let mut bounds = BTreeSet::new();
+        for (set, _) in nfa.edges.iter().flatten() {
+            for &(lo, hi) in set {
+                bounds.insert(lo);
+                bounds.insert(hi + 1);
+            }
+        }
+        let bounds = bounds.into_iter().collect::<Vec<_>>();
+
+        let mut ids = HashMap::new();
+        let mut subsets = vec![nfa.closure([start])];
+        ids.insert(subsets[0].clone(), 0);
+        let mut transitions = vec![];
+        while transitions.len() < subsets.len() {
+            let subset = subsets[transitions.len()].clone();
+            let mut trans: Vec<(u32, u32, usize)> = vec![];
+            for w in bounds.windows(2) {
+                let targets = subset
+                    .iter()
+                    .flat_map(|&s| &nfa.edges[s])
+                    .filter(|(set, _)| set.iter().any(|&(lo, hi)| lo <= w[0] && w[0] <= hi))
+                    .map(|&(_, to)| to);
+                let target = nfa.closure(targets);
+                if target.is_empty() {
+                    continue;
+                }
+                let next = match ids.get(&target) {
+                    Some(&id) => id,
+                    None => {
+                        if subsets.len() >= MAX_STATES {
+                            return Err(format!("Regex needs more than {} states", MAX_STATES));
+                        }
+                        subsets.push(target.clone());
+                        ids.insert(target, subsets.len() - 1);
+                        subsets.len() - 1
+                    }
+                };
+                match trans.last_mut() {
+                    Some(last) if last.2 == next && last.1 + 1 == w[0] => last.1 = w[1] - 1,
+                    _ => trans.push((w[0], w[1] - 1, next)),
+                }
+            }
+            transitions.push(trans);
+        }
+        let accepting = subsets.iter().map(|s| s.contains(&end)).collect::<Vec<_>>();
+
+        // drop edges into states that can't reach a match
+        let mut live = accepting.clone();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for s in 0..transitions.len() {
+                if !live[s] && transitions[s].iter().any(|&(_, _, n)| live[n]) {
+                    live[s] = true;
+                    changed = true;
+                }
+            }
+        }
+        if !live[0] {
+            return Err("Regex matches nothing".into());
+        }
+        transitions
+            .iter_mut()
+            .for_each(|t| t.retain(|&(_, _, n)| live[n]));
+
+        Ok(Self {
+            transitions,
+            accepting,
+        })
+    }
+
+    pub fn start(&self) -> usize {
+        0
+    }
+
+    /// State after `c`, `None` if no match can follow
+    pub fn next(&self, state: usize, c: char) -> Option<usize> {
+        let trans = &self.transitions[state];
+        let c = c as u32;
+        let idx = trans.partition_point(|&(_, hi, _)| hi < c);
+        trans.get(idx).filter(|&&(lo, _, _)| lo <= c).map(|t| t.2)
+    }
+
+    pub fn is_accepting(&self, state: usize) -> bool {
+        self.accepting[state]
+    }
+
+    pub fn num_states(&self) -> usize {
+        self.transitions.len()
+    }
+
+    /// The whole of `text` matches
+    pub fn is_match(&self, text: &str) -> bool {
+        text.chars()
+            .try_fold(self.start(), |s, c| self.next(s, c))
+            .is_some_and(|s| self.is_accepting(s))
+    }
+}
+
+/// A regex with the tokens allowed from each of its states, shared by every generation using it
+pub struct RegexIndex {
+    regex: Regex,
+    trie: Arc<TokenTrie>,
+    allowed: Vec<Vec<usize>>,
+}
+
+impl RegexIndex {
+    pub fn new(regex: Regex, trie: Arc<TokenTrie>) -> Self {
+        let allowed = (0..regex.num_states())
+            .map(|state| {
+                let mut allowed = vec![false; trie.vocab_size()];
+                trie.visit(state, &mut |&s, c| regex.next(s, c), &mut allowed);
+                allowed[EOS] = regex.is_accepting(state);
+                (0..allowed.len()).filter(|&t| allowed[t]).collect()
+            })
+            .collect();
+        Self {
+            regex,
+            trie,
+            allowed,
+        }
+    }
+
+    pub fn regex(&self) -> &Regex {
+        &self.regex
+    }
+}
+
+/// Mask logits of tokens that would leave the regex without a match, EOS is only allowed
+/// once the generated text matches. Pair with a `StopTokens(vec![EOS])`.
+pub struct RegexConstraint {
+    index: Arc<RegexIndex>,
+    state: usize,
+    /// History length when generation started, the prompt is not constrained
+    start: Option<usize>,
+    /// Generated tokens fed to the DFA so far
+    consumed: usize,
+    mask: Vec<bool>,
+}
+
+impl RegexConstraint {
+    pub fn new(index: Arc<RegexIndex>) -> Self {
+        Self {
+            state: index.regex.start(),
+            index,
+            start: None,
+            consumed: 0,
+            mask: vec![],
+        }
+    }
+}
+
+impl LogitsProcessor for RegexConstraint {
+    fn process(&mut self, history: &[usize], logits: &mut [Ty]) {
+        let start = *self.start.get_or_insert(history.len());
+        let RegexIndex { regex, trie, .. } = &*self.index;
+        for &token in &history[start + self.consumed..] {
+            for c in trie.text(token).chars() {
+                // only allowed tokens get sampled, so this can't fail
+                self.state = regex.next(self.state, c).unwrap();
+            }
+            self.consumed += 1;
+        }
+
+        self.mask.clear();
+        self.mask.resize(logits.len(), false);
+        self.index.allowed[self.state]
+            .iter()
+            .for_each(|&t| self.mask[t] = true);
+        mask_logits(&self.mask, logits);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classes_and_repeats() {
+        let re = Regex::new(r"^[A-Z][a-z]*\d{2,3}$").unwrap();
+        assert!(re.is_match("Ab12"));
+        assert!(re.is_match("X123"));
+        assert!(!re.is_match("X1"));
+        assert!(!re.is_match("X1234"));
+        assert!(!re.is_match("ab12"));
+
+        let re = Regex::new(r"[^,\s]+(, [^,\s]+){0,2}").unwrap();
+        assert!(re.is_match("a"));
+        assert!(re.is_match("a, b, c"));
+        assert!(!re.is_match("a, b, c, d"));
+        assert!(!re.is_match("a,b"));
+    }
+
+    #[test]
+    fn alternation_and_groups() {
+        let re = Regex::new(r"(?:yes|no)(!|\.)?|maybe").unwrap();
+        for text in ["yes", "no!", "no.", "maybe"] {
+            assert!(re.is_match(text), "{}", text);
+        }
+        for text in ["", "yes!!", "maybe!", "ye"] {
+            assert!(!re.is_match(text), "{}", text);
+        }
+        assert!(Regex::new(r"\w+@\w+\.com").unwrap().is_match("me@host.com"));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Regex::new(r"[^\s\S]")
+            .unwrap_err()
+            .contains("matches nothing"));
+        assert!(Regex::new("a)").is_err());
+        assert!(Regex::new("(a").is_err());
+        assert!(Regex::new("a{3,2}").is_err());
+        assert!(Regex::new("(a{1000}){1000}")
+            .unwrap_err()
+            .contains("NFA states"));
+        assert!(Regex::new("(a{1000}|b)").is_ok());
+    }
+}
@@ -0,0 +1,478 @@
+//! Turning logits into the next token.
+//! A [`Pipeline`] runs a chain of [`LogitsProcessor`]s over the logits, picks a token with a
+//! [`Sampler`] and asks its [`StoppingCriteria`] whether to go on.
+use std::collections::HashMap;
+
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use serde_json::{json, Value};
+
+use crate::{inplace_softmax, Ty, Vocab, EOS};
+
+/// Mutate logits before sampling, `history` is every token of the sequence so far
+pub trait LogitsProcessor {
+    fn process(&mut self, history: &[usize], logits: &mut [Ty]);
+}
+
+/// Pick the next token from (processed) logits
+pub trait Sampler {
+    fn sample(&mut self, logits: &[Ty]) -> usize;
+}
+
+/// Decide if generation is done after sampling `next`
+pub trait StoppingCriteria {
+    fn should_stop(&mut self, history: &[usize], next: usize) -> bool;
+}
+
+impl<P: LogitsProcessor + ?Sized> LogitsProcessor for Box<P> {
+    fn process(&mut self, history: &[usize], logits: &mut [Ty]) {
+        (**self).process(history, logits)
+    }
+}
+
+/// Processors run in order
+impl<P: LogitsProcessor> LogitsProcessor for Vec<P> {
+    fn process(&mut self, history: &[usize], logits: &mut [Ty]) {
+        self.iter_mut().for_each(|p| p.process(history, logits));
+    }
+}
+
+impl<S: StoppingCriteria + ?Sized> StoppingCriteria for Box<S> {
+    fn should_stop(&mut self, history: &[usize], next: usize) -> bool {
+        (**self).should_stop(history, next)
+    }
+}
+
+/// Stop as soon as any of the criteria does
+impl<S: StoppingCriteria> StoppingCriteria for Vec<S> {
+    fn should_stop(&mut self, history: &[usize], next: usize) -> bool {
+        self.iter_mut().any(|s| s.should_stop(history, next))
+    }
+}
+
+/// Penalties on tokens that already appeared in the last `window` tokens of the sequence
+#[derive(Debug, Clone, Copy)]
+pub struct Penalties {
+    /// CTRL style: divide positive logits (multiply negative ones) of seen tokens. 1.0 is off.
+    pub repetition: Ty,
+    /// Subtracted from a token logit once per occurrence. 0.0 is off.
+    pub frequency: Ty,
+    /// Subtracted from a token logit if it occurred at all. 0.0 is off.
+    pub presence: Ty,
+    /// Number of most recent tokens to look at
+    pub window: usize,
+}
+
+impl Default for Penalties {
+    fn default() -> Self {
+        Self {
+            repetition: 1 as Ty,
+            frequency: 0 as Ty,
+            presence: 0 as Ty,
+            window: 64,
+        }
+    }
+}
+
+impl Penalties {
+    pub fn is_off(&self) -> bool {
+        self.repetition == 1 as Ty && self.frequency == 0 as Ty && self.presence == 0 as Ty
+    }
+}
+
+impl LogitsProcessor for Penalties {
+    fn process(&mut self, history: &[usize], logits: &mut [Ty]) {
+        if self.is_off() {
+            return;
+        }
+        let recent = &history[history.len().saturating_sub(self.window)..];
+        let mut counts = HashMap::<usize, usize>::new();
+        recent
+            .iter()
+            .for_each(|&t| *counts.entry(t).or_default() += 1);
+
+        for (token, count) in counts {
+            let logit = &mut logits[token];
+            if *logit > 0 as Ty {
+                *logit /= self.repetition;
+            } else {
+                *logit *= self.repetition;
+            }
+            *logit -= self.frequency * count as Ty + self.presence;
+        }
+    }
+}
+
+/// Divide logits by the temperature
+pub struct Temperature(pub Ty);
+
+impl LogitsProcessor for Temperature {
+    fn process(&mut self, _history: &[usize], logits: &mut [Ty]) {
+        logits.iter_mut().for_each(|l| *l /= self.0);
+    }
+}
+
+/// Keep only the `k` largest logits
+pub struct TopK(pub usize);
+
+impl LogitsProcessor for TopK {
+    fn process(&mut self, _history: &[usize], logits: &mut [Ty]) {
+        if self.0 == 0 || self.0 >= logits.len() {
+            return;
+        }
+        let mut sorted = logits.to_vec();
+        let (_, &mut kth, _) = sorted.select_nth_unstable_by(self.0 - 1, |a, b| b.total_cmp(a));
+        logits
+            .iter_mut()
+            .filter(|l| **l < kth)
+            .for_each(|l| *l = Ty::NEG_INFINITY);
+    }
+}
+
+/// Nucleus: keep the smallest set of most likely tokens whose probability adds up to `p`
+pub struct TopP(pub Ty);
+
+impl LogitsProcessor for TopP {
+    fn process(&mut self, _history: &[usize], logits: &mut [Ty]) {
+        if self.0 >= 1 as Ty {
+            return;
+        }
+        let mut probs = logits.to_vec();
+        inplace_softmax(&mut probs);
+        let mut order = (0..logits.len()).collect::<Vec<_>>();
+        order.sort_unstable_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+
+        let mut cdf = 0 as Ty;
+        let keep = order
+            .iter()
+            .position(|&idx| {
+                cdf += probs[idx];
+                cdf >= self.0
+            })
+            .map_or(order.len(), |last| last + 1);
+        order[keep..]
+            .iter()
+            .for_each(|&idx| logits[idx] = Ty::NEG_INFINITY);
+    }
+}
+
+/// Always take the most likely token
+pub struct Greedy;
+
+impl Sampler for Greedy {
+    fn sample(&mut self, logits: &[Ty]) -> usize {
+        argmax(logits)
+    }
+}
+
+/// Sample from the softmax of the logits
+pub struct Multinomial {
+    rng: SmallRng,
+    probs: Vec<Ty>,
+}
+
+impl Multinomial {
+    /// Seeded from entropy when `seed` is `None`
+    pub fn new(seed: Option<u64>) -> Self {
+        Self {
+            rng: seed.map_or_else(SmallRng::from_entropy, SmallRng::seed_from_u64),
+            probs: vec![],
+        }
+    }
+}
+
+impl Sampler for Multinomial {
+    fn sample(&mut self, logits: &[Ty]) -> usize {
+        self.probs.clear();
+        self.probs.extend_from_slice(logits);
+        inplace_softmax(&mut self.probs);
+        cdf_sample(&self.probs, self.rng.gen::<Ty>())
+    }
+}
+
+/// Stop once the sequence holds this many tokens
+pub struct MaxLength(pub usize);
+
+impl StoppingCriteria for MaxLength {
+    fn should_stop(&mut self, history: &[usize], _next: usize) -> bool {
+        history.len() >= self.0
+    }
+}
+
+/// Stop after sampling one of these tokens (e.g. EOS)
+pub struct StopTokens(pub Vec<usize>);
+
+impl StoppingCriteria for StopTokens {
+    fn should_stop(&mut self, _history: &[usize], next: usize) -> bool {
+        self.0.contains(&next)
+    }
+}
+
+/// Everything needed to pick tokens
+pub struct Pipeline {
+    pub processors: Vec<Box<dyn LogitsProcessor + Send>>,
+    pub sampler: Box<dyn Sampler + Send>,
+    pub stop: Vec<Box<dyn StoppingCriteria + Send>>,
+}
+
+impl Pipeline {
+    pub fn new(sampler: impl Sampler + Send + 'static) -> Self {
+        Self {
+            processors: vec![],
+            sampler: Box::new(sampler),
+            stop: vec![],
+        }
+    }
+
+    /// Add a processor at the end of the chain
+    pub fn with(mut self, processor: impl LogitsProcessor + Send + 'static) -> Self {
+        self.processors.push(Box::new(processor));
+        self
+    }
+
+    /// Add a processor at the start of the chain,
+    /// so constraints mask logits before any sampling knob sees them
+    pub fn constrain(mut self, processor: impl LogitsProcessor + Send + 'static) -> Self {
+        self.processors.insert(0, Box::new(processor));
+        self
+    }
+
+    /// Also stop when `criteria` does
+    pub fn stop_when(mut self, criteria: impl StoppingCriteria + Send + 'static) -> Self {
+        self.stop.push(Box::new(criteria));
+        self
+    }
+
+    /// Run the processors over `logits` and sample from the result
+    pub fn next_token(&mut self, history: &[usize], logits: &mut [Ty]) -> usize {
+        self.processors.process(history, logits);
+        self.sampler.sample(logits)
+    }
+
+    pub fn should_stop(&mut self, history: &[usize], next: usize) -> bool {
+        self.stop.should_stop(history, next)
+    }
+}
+
+/// The built-in sampling knobs
+#[derive(Debug, Clone, Copy)]
+pub struct SamplingParams {
+    /// 0.0 is greedy
+    pub temperature: Ty,
+    /// 0 is off
+    pub top_k: usize,
+    /// 1.0 is off
+    pub top_p: Ty,
+    pub penalties: Penalties,
+    pub seed: Option<u64>,
+}
+
+impl Default for SamplingParams {
+    fn default() -> Self {
+        Self {
+            temperature: 0 as Ty,
+            top_k: 0,
+            top_p: 1 as Ty,
+            penalties: Penalties::default(),
+            seed: None,
+        }
+    }
+}
+
+impl SamplingParams {
+    /// Penalties -> temperature -> top k -> top p -> sample (no stopping criteria)
+    pub fn pipeline(&self) -> Pipeline {
+        if self.temperature == 0 as Ty {
+            return Pipeline::new(Greedy).with(self.penalties);
+        }
+        Pipeline::new(Multinomial::new(self.seed))
+            .with(self.penalties)
+            .with(Temperature(self.temperature))
+            .with(TopK(self.top_k))
+            .with(TopP(self.top_p))
+    }
+
+    /// Knobs of an OpenAI style request: `temperature` (1.0 when missing, like OpenAI), `top_k`,
+    /// `top_p`, `repetition_penalty`, `frequency_penalty`, `presence_penalty` and `seed`
+    pub fn from_json(body: &Value) -> Result<Self, String> {
+        let defaults = Penalties::default();
+        Ok(Self {
+            temperature: opt_num(body, "temperature")?.unwrap_or(1 as Ty),
+            top_k: opt_usize(body, "top_k")?.unwrap_or(0),
+            top_p: opt_num(body, "top_p")?.unwrap_or(1 as Ty),
+            penalties: Penalties {
+                repetition: opt_num(body, "repetition_penalty")?.unwrap_or(defaults.repetition),
+                frequency: opt_num(body, "frequency_penalty")?.unwrap_or(defaults.frequency),
+                presence: opt_num(body, "presence_penalty")?.unwrap_or(defaults.presence),
+                window: defaults.window,
+            },
+            seed: opt_usize(body, "seed")?.map(|s| s as u64),
+        })
+    }
+}
+
+/// Optional number field of a JSON object
+pub(crate) fn opt_num(body: &Value, key: &str) -> Result<Option<Ty>, String> {
+    match &body[key] {
+        Value::Null => Ok(None),
+        v => v
+            .as_f64()
+            .map(|v| Some(v as Ty))
+            .ok_or(format!("{} must be a number", key)),
+    }
+}
+
+/// Optional non negative integer field of a JSON object
+pub(crate) fn opt_usize(body: &Value, key: &str) -> Result<Option<usize>, String> {
+    match &body[key] {
+        Value::Null => Ok(None),
+        v => v
+            .as_u64()
+            .map(|v| Some(v as usize))
+            .ok_or(format!("{} must be a non negative integer", key)),
+    }
+}
+
+pub fn argmax(v: &[Ty]) -> usize {
+    v.iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(index, _)| index)
+        .unwrap()
+}
+
+/// A sampled token with its log probability and the most likely tokens at its position,
+/// from the model logits before any processing
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenLogprobs {
+    pub token: usize,
+    pub logprob: Ty,
+    /// `(token, logprob)`, most likely first
+    pub top: Vec<(usize, Ty)>,
+}
+
+/// Generated text with per token log probabilities, in the shape of OpenAI `logprobs`.
+/// Tokens have their raw `bytes` too, a byte token is only part of a character.
+pub fn logprobs_json(vocab: &Vocab, generated: &[TokenLogprobs]) -> Value {
+    let entry = |token: usize, logprob: Ty| {
+        json!({
+            "id": token,
+            "token": vocab.token_text(token),
+            "bytes": vocab.token_bytes(token),
+            "logprob": logprob,
+        })
+    };
+    let tokens = generated
+        .iter()
+        .map(|t| {
+            let mut out = entry(t.token, t.logprob);
+            out["top_logprobs"] = t.top.iter().map(|&(t, l)| entry(t, l)).collect();
+            out
+        })
+        .collect::<Vec<_>>();
+    let bytes = generated
+        .iter()
+        .filter(|t| t.token != EOS)
+        .flat_map(|t| vocab.token_bytes(t.token))
+        .copied()
+        .collect::<Vec<_>>();
+    json!({ "text": String::from_utf8_lossy(&bytes), "logprobs": tokens })
+}
+
+/// The `n` most likely `(token, logprob)`, most likely first
+pub fn top_logprobs(logprobs: &[Ty], n: usize) -> Vec<(usize, Ty)> {
+    let n = n.min(logprobs.len());
+    if n == 0 {
+        return vec![];
+    }
+    let mut tokens = (0..logprobs.len()).collect::<Vec<_>>();
+    tokens.select_nth_unstable_by(n - 1, |&a, &b| logprobs[b].total_cmp(&logprobs[a]));
+    tokens.truncate(n);
+    tokens.sort_unstable_by(|&a, &b| logprobs[b].total_cmp(&logprobs[a]));
+    tokens.into_iter().map(|t| (t, logprobs[t])).collect()
+}
+
+/// Natural log of the softmax of `logits`
+pub fn log_softmax(logits: &[Ty]) -> Vec<Ty> {
+    let max = logits.iter().fold(Ty::NEG_INFINITY, |acc, &l| acc.max(l));
+    let log_sum = logits.iter().map(|&l| (l - max).exp()).sum::<Ty>().ln();
+    logits.iter().map(|&l| l - max - log_sum).collect()
+}
+
+/// Index where the cumulative sum of `probs` passes `r` (in [0, 1))
+fn cdf_sample(probs: &[Ty], r: Ty) -> usize {
+    let mut cdf = 0 as Ty;
+    for (idx, p) in probs.iter().enumerate() {
+        cdf += *p;
+        if r < cdf {
+            return idx;
+        }
+    }
+    // rounding left `r` past the total, masked out tokens have p = 0 and must not come out
+    probs
+        .iter()
+        .rposition(|&p| p > 0 as Ty)
+        .unwrap_or(probs.len() - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKENIZER: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../tokenizer.bin");
+
+    #[test]
+    fn penalties() {
+        let logits = [2.0, -1.0, 0.5, 3.0, -2.0, 1.0];
+        // the first 0 is out of the window: 0 and 1 twice, 4 and 5 once
+        let history = [0, 1, 1, 4, 0, 0, 5];
+        let run = |repetition, frequency, presence| {
+            let mut penalties = Penalties {
+                repetition,
+                frequency,
+                presence,
+                window: 6,
+            };
+            let mut out = logits;
+            penalties.process(&history, &mut out);
+            out
+        };
+        assert_eq!(run(1.0, 0.0, 0.0), logits);
+        assert_eq!(run(2.0, 0.0, 0.0), [1.0, -2.0, 0.5, 3.0, -4.0, 0.5]);
+        assert_eq!(run(1.0, 0.5, 0.0), [1.0, -2.0, 0.5, 3.0, -2.5, 0.5]);
+        assert_eq!(run(1.0, 0.0, 0.25), [1.75, -1.25, 0.5, 3.0, -2.25, 0.75]);
+        assert_eq!(run(2.0, 0.5, 0.25), [-0.25, -3.25, 0.5, 3.0, -4.75, -0.25]);
+    }
+
+    #[test]
+    fn logprobs_json_keeps_non_ascii() {
+        let vocab = Vocab::from_file(32000, TOKENIZER);
+        let text = "Grüße 🦀";
+        let generated = vocab
+            .encode(text)
+            .into_iter()
+            .chain([EOS])
+            .map(|token| TokenLogprobs {
+                token,
+                logprob: -0.5,
+                top: vec![(token, -0.5)],
+            })
+            .collect::<Vec<_>>();
+        let json = logprobs_json(&vocab, &generated);
+        // the tokenizer's leading space
+        assert_eq!(json["text"], format!(" {}", text));
+        let bytes = json["logprobs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .take(generated.len() - 1)
+            .flat_map(|t| t["bytes"].as_array().unwrap().clone())
+            .map(|b| b.as_u64().unwrap() as u8)
+            .collect::<Vec<_>>();
+        assert_eq!(bytes, format!(" {}", text).as_bytes());
+        let tokens = json["logprobs"].as_array().unwrap();
+        assert!(tokens
+            .iter()
+            .all(|t| !t["token"].as_str().unwrap().starts_with("<0x")));
+    }
+}
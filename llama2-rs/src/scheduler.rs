@@ -0,0 +1,303 @@
+//! Continuous batching of concurrent generations.
+//! The batch runs in steps: every step feeds one token to each decoding sequence and spends a
+//! budget of prompt tokens on the sequences still prefilling, so long prompts don't stall running
+//! generations. The sequences of a step run in parallel with the `parallel` feature. Requests join
+//! the batch between steps and leave it as soon as they finish, within limits on the batch size
+//! and K, V memory.
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, RecvError, Sender};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::kv::{blocks_for, BlockPool, KVFormat, SharedPool};
+use crate::sampling::{log_softmax, top_logprobs, Pipeline, TokenLogprobs};
+use crate::session::{PrefixCache, Session};
+use crate::{Config, LamaExecuter, Ty, EOS};
+
+#[derive(Debug, Clone, Copy)]
+pub struct BatchParams {
+    /// Max number of sequences in the batch
+    pub max_batch: usize,
+    /// K, V blocks for the batch, each sequence reserves enough for its prompt and
+    /// `max_tokens` (up to `seq_len`) when it joins
+    pub max_kv_blocks: usize,
+    /// Prompt tokens computed per step, across the prefilling sequences
+    pub prefill_chunk: usize,
+}
+
+impl BatchParams {
+    /// `max_batch` sequences that can all use the whole context
+    pub fn new(cfg: &Config, max_batch: usize) -> Self {
+        Self {
+            max_batch,
+            max_kv_blocks: max_batch * blocks_for(cfg.seq_len),
+            prefill_chunk: 64,
+        }
+    }
+}
+
+pub struct SequenceRequest {
+    /// BOS included, shorter than `seq_len`
+    pub prompt: Vec<usize>,
+    pub pipeline: Pipeline,
+    pub max_tokens: usize,
+    /// Number of top alternatives, when log probabilities are wanted
+    pub logprobs: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    /// Sampled EOS
+    Eos,
+    /// Reached `max_tokens` or the end of the context
+    Length,
+}
+
+pub enum Event {
+    /// A sampled token (not EOS), with its log probabilities when requested
+    Token(usize, Option<TokenLogprobs>),
+    Done(FinishReason),
+    /// The sequence could not go on (e.g. out of K, V blocks)
+    Error(String),
+}
+
+/// Receiving end of a request, dropping it cancels the request
+pub struct Events {
+    receiver: Receiver<Event>,
+    /// Only here for the scheduler to see when we are gone
+    _alive: Arc<()>,
+}
+
+impl Events {
+    /// Next event, fails if the scheduler is gone
+    pub fn recv(&self) -> Result<Event, RecvError> {
+        self.receiver.recv()
+    }
+}
+
+/// A submitted request, with the channel of its events and its cancellation
+type Queued = (SequenceRequest, Sender<Event>, Weak<()>);
+
+struct Sequence {
+    session: Session,
+    request: SequenceRequest,
+    generated: usize,
+    /// Reserved K, V blocks
+    blocks: usize,
+    /// Prompt blocks are in the prefix cache
+    cached: bool,
+    events: Sender<Event>,
+    /// Dead once the request is cancelled
+    alive: Weak<()>,
+}
+
+impl Sequence {
+    fn prefilling(&self) -> bool {
+        self.session.pos() < self.request.prompt.len()
+    }
+
+    /// Run the step of this sequence: feed its prompt up to `end` while prefilling, then
+    /// (once the prompt is in) sample the next token. True once it is finished.
+    fn step<W: LamaExecuter<Vec<Ty>>>(&mut self, end: usize, weights: &W, cfg: &Config) -> bool {
+        while self.session.pos() < end {
+            if self.alive.strong_count() == 0 {
+                return true;
+            }
+            let token = self.request.prompt[self.session.pos()];
+            if let Err(e) = self.session.feed(weights, cfg, token) {
+                let _ = self.events.send(Event::Error(e));
+                return true;
+            }
+        }
+        // a prompt completed in this step starts decoding right away
+        !self.prefilling() && self.decode(weights, cfg)
+    }
+
+    /// Sample the next token of a prefilled sequence and feed it, true once it is finished
+    fn decode<W: LamaExecuter<Vec<Ty>>>(&mut self, weights: &W, cfg: &Config) -> bool {
+        let finish = |seq: &Self, reason| {
+            let _ = seq.events.send(Event::Done(reason));
+            true
+        };
+        if self.generated >= self.request.max_tokens {
+            return finish(self, FinishReason::Length);
+        }
+        // before the pipeline, processors change the logits in place
+        let dist = self
+            .request
+            .logprobs
+            .map(|_| log_softmax(&self.session.state.logits));
+        let (history, logits) = self.session.logits_mut();
+        let next = self.request.pipeline.next_token(history, logits);
+        if next == EOS {
+            return finish(self, FinishReason::Eos);
+        }
+        let logprobs = self
+            .request
+            .logprobs
+            .zip(dist)
+            .map(|(top_n, dist)| TokenLogprobs {
+                token: next,
+                logprob: dist[next],
+                top: top_logprobs(&dist, top_n),
+            });
+        self.generated += 1;
+        if self.events.send(Event::Token(next, logprobs)).is_err() {
+            // cancelled
+            return true;
+        }
+        if self.generated >= self.request.max_tokens || self.session.pos() >= cfg.seq_len {
+            return finish(self, FinishReason::Length);
+        }
+        if let Err(e) = self.session.feed(weights, cfg, next) {
+            let _ = self.events.send(Event::Error(e));
+            return true;
+        }
+        false
+    }
+}
+
+pub struct Scheduler {
+    params: BatchParams,
+    seq_len: usize,
+    pool: SharedPool,
+    /// Blocks kept by the prefix cache, on top of `max_kv_blocks`
+    cached_blocks: usize,
+    waiting: Mutex<VecDeque<Queued>>,
+    wakeup: Condvar,
+}
+
+impl Scheduler {
+    pub fn new(cfg: &Config, kv_format: KVFormat, params: BatchParams) -> Self {
+        assert!(
+            params.max_batch > 0,
+            "Batch must hold at least one sequence"
+        );
+        assert!(params.prefill_chunk > 0, "Prefill chunk must be at least 1");
+        // a context worth of cached prompt blocks
+        let cached_blocks = blocks_for(cfg.seq_len);
+        let pool = BlockPool::new(cfg, params.max_kv_blocks + cached_blocks, kv_format);
+        Self {
+            params,
+            seq_len: cfg.seq_len,
+            pool: Arc::new(Mutex::new(pool)),
+            cached_blocks,
+            waiting: Mutex::new(VecDeque::new()),
+            wakeup: Condvar::new(),
+        }
+    }
+
+    /// Queue a request, its tokens come through the returned channel ending with
+    /// [`Event::Done`] or [`Event::Error`]. Dropping the receiver cancels the request.
+    pub fn submit(&self, request: SequenceRequest) -> Result<Events, String> {
+        if request.prompt.is_empty() || request.prompt.len() >= self.seq_len {
+            return Err(format!(
+                "Prompt is {} tokens, the context is {}",
+                request.prompt.len(),
+                self.seq_len
+            ));
+        }
+        if self.reservation(&request) > self.params.max_kv_blocks {
+            return Err("Request needs more K, V memory than the server has".into());
+        }
+        let (sender, receiver) = mpsc::channel();
+        let alive = Arc::new(());
+        let waiting = (request, sender, Arc::downgrade(&alive));
+        self.waiting.lock().unwrap().push_back(waiting);
+        self.wakeup.notify_one();
+        Ok(Events {
+            receiver,
+            _alive: alive,
+        })
+    }
+
+    /// Blocks a request may fill: its prompt and `max_tokens`, within the context
+    fn reservation(&self, request: &SequenceRequest) -> usize {
+        let tokens = request.prompt.len().saturating_add(request.max_tokens);
+        blocks_for(tokens.min(self.seq_len))
+    }
+
+    /// Run the batch forever
+    pub fn run<W: LamaExecuter<Vec<Ty>> + Sync>(&self, weights: &W, cfg: &Config) {
+        let mut prefix_cache = PrefixCache::new(self.cached_blocks);
+        let mut running: Vec<Sequence> = vec![];
+        let mut reserved = 0;
+        loop {
+            self.admit(cfg, &mut running, &mut reserved, &mut prefix_cache);
+
+            // prompt budget goes oldest first, so prompts complete in arrival order
+            let mut budget = self.params.prefill_chunk;
+            let ends = running
+                .iter()
+                .map(|seq| {
+                    let pos = seq.session.pos();
+                    let end = (pos + budget).min(seq.request.prompt.len()).max(pos);
+                    budget -= end - pos;
+                    end
+                })
+                .collect::<Vec<_>>();
+            let step = |(seq, end): (&mut Sequence, usize)| seq.step(end, weights, cfg);
+            #[cfg(feature = "parallel")]
+            let done = running
+                .par_iter_mut()
+                .zip(ends)
+                .map(step)
+                .collect::<Vec<_>>();
+            #[cfg(not(feature = "parallel"))]
+            let done = running.iter_mut().zip(ends).map(step).collect::<Vec<_>>();
+
+            for seq in running.iter_mut() {
+                if !seq.cached && !seq.prefilling() {
+                    prefix_cache.store(&seq.session, &seq.request.prompt);
+                    seq.cached = true;
+                }
+            }
+            let mut done = done.into_iter();
+            running.retain(|seq| {
+                let done = done.next().unwrap();
+                if done {
+                    reserved -= seq.blocks;
+                }
+                !done
+            });
+        }
+    }
+
+    /// Move waiting requests into the batch while it has room, waiting for one if it is empty
+    fn admit(
+        &self,
+        cfg: &Config,
+        running: &mut Vec<Sequence>,
+        reserved: &mut usize,
+        prefix_cache: &mut PrefixCache,
+    ) {
+        let mut waiting = self.waiting.lock().unwrap();
+        while running.is_empty() && waiting.is_empty() {
+            waiting = self.wakeup.wait(waiting).unwrap();
+        }
+        while running.len() < self.params.max_batch {
+            let Some((request, _, _)) = waiting.front() else {
+                break;
+            };
+            let blocks = self.reservation(request);
+            if *reserved + blocks > self.params.max_kv_blocks {
+                break;
+            }
+            let (request, events, alive) = waiting.pop_front().unwrap();
+            let mut session = Session::new(cfg, &self.pool);
+            prefix_cache.share(&mut session, &request.prompt);
+            *reserved += blocks;
+            running.push(Sequence {
+                session,
+                request,
+                generated: 0,
+                blocks,
+                cached: false,
+                events,
+                alive,
+            });
+        }
+    }
+}
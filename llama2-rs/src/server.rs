@@ -0,0 +1,648 @@
+//! OpenAI compatible completion server.
+//! `POST /v1/completions`, `POST /v1/chat/completions` and `GET /v1/models` over a minimal
+//! HTTP/1.1 implementation on std's `TcpListener`, one thread per connection.
+//! Requests share the weights and run together in a continuously batched [`Scheduler`].
+//! With `"stream": true` tokens are sent as server-sent events as they are generated,
+//! and generation stops once the client is gone.
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::chat::{ChatTemplate, Message};
+use crate::kv::KVFormat;
+use crate::sampling::{opt_usize, SamplingParams, TokenLogprobs};
+use crate::scheduler::{BatchParams, Event, Events, FinishReason, Scheduler, SequenceRequest};
+use crate::text::TextStream;
+use crate::{Config, Llama2CPUFloat, Ty, Vocab, BOS};
+
+/// Largest request body we read
+const MAX_BODY: usize = 1 << 20;
+/// Longest request line or header line we read
+const MAX_HEAD_LINE: usize = 8 << 10;
+/// Largest request line and headers we read, together
+const MAX_HEAD: usize = 64 << 10;
+/// Clients that send nothing for this long are dropped
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// JSON response, or `None` once it streamed the response itself
+type Handler = fn(&Server, &Value, &mut TcpStream) -> Result<Option<Value>, HttpError>;
+
+/// A failed request, plain `String` errors are the client's fault
+struct HttpError {
+    status: u16,
+    message: String,
+}
+
+impl HttpError {
+    fn unavailable(message: String) -> Self {
+        Self {
+            status: 503,
+            message,
+        }
+    }
+
+    fn internal(message: &str) -> Self {
+        Self {
+            status: 500,
+            message: message.to_string(),
+        }
+    }
+
+    fn json(&self) -> Value {
+        let kind = if self.status >= 500 {
+            "server_error"
+        } else {
+            "invalid_request_error"
+        };
+        json!({ "error": { "message": self.message, "type": kind } })
+    }
+}
+
+impl From<String> for HttpError {
+    fn from(message: String) -> Self {
+        Self {
+            status: 400,
+            message,
+        }
+    }
+}
+
+impl From<&str> for HttpError {
+    fn from(message: &str) -> Self {
+        message.to_string().into()
+    }
+}
+
+pub struct Server {
+    weights: Llama2CPUFloat,
+    cfg: Config,
+    vocab: Vocab,
+    scheduler: Scheduler,
+    /// Renders `/v1/chat/completions` conversations
+    template: ChatTemplate,
+    /// Name reported in responses and `/v1/models`
+    model: String,
+    next_id: AtomicUsize,
+}
+
+/// A validated completion request
+struct Request {
+    prompt: Vec<usize>,
+    params: SamplingParams,
+    max_tokens: usize,
+    stop: Vec<String>,
+    /// Number of top alternatives, when log probabilities are requested
+    logprobs: Option<usize>,
+}
+
+struct Completion {
+    text: String,
+    /// Per generated token (EOS excluded), when requested
+    logprobs: Vec<TokenLogprobs>,
+    finish_reason: &'static str,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+}
+
+impl Server {
+    pub fn new(
+        weights: Llama2CPUFloat,
+        cfg: Config,
+        vocab: Vocab,
+        kv_format: KVFormat,
+        batch: BatchParams,
+        template: ChatTemplate,
+        model: &str,
+    ) -> Self {
+        Self {
+            scheduler: Scheduler::new(&cfg, kv_format, batch),
+            weights,
+            cfg,
+            vocab,
+            template,
+            model: model.to_string(),
+            next_id: AtomicUsize::new(0),
+        }
+    }
+
+    /// Accept connections on `addr` (e.g. `127.0.0.1:8080`) forever
+    pub fn serve(self: Arc<Self>, addr: &str) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        let server = Arc::clone(&self);
+        thread::spawn(move || server.scheduler.run(&server.weights, &server.cfg));
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let server = Arc::clone(&self);
+            thread::spawn(move || server.handle(stream));
+        }
+        Ok(())
+    }
+
+    fn handle(&self, mut stream: TcpStream) {
+        let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
+        let response = match read_request(&mut stream) {
+            Ok((method, path, body)) => self.route(&method, &path, &body, &mut stream),
+            Err(e) => Some((400, error_json(&e))),
+        };
+        if let Some((status, body)) = response {
+            // nothing to do if the client is gone
+            let _ = write_json(&mut stream, status, &body);
+        }
+    }
+
+    /// Status and JSON body of the response, `None` if it was streamed already
+    fn route(
+        &self,
+        method: &str,
+        path: &str,
+        body: &[u8],
+        stream: &mut TcpStream,
+    ) -> Option<(u16, Value)> {
+        let path = path.split('?').next().unwrap_or_default();
+        let handler: Handler = match (method, path) {
+            ("POST", "/v1/completions") => Self::completions,
+            ("POST", "/v1/chat/completions") => Self::chat_completions,
+            ("GET", "/v1/models") => return Some((200, self.models())),
+            (_, "/v1/completions" | "/v1/chat/completions" | "/v1/models") => {
+                return Some((405, error_json("Method not allowed")))
+            }
+            _ => return Some((404, error_json(&format!("No route for {}", path)))),
+        };
+        let body = match serde_json::from_slice::<Value>(body) {
+            Ok(body) if body.is_object() => body,
+            Ok(_) => return Some((400, error_json("Request body must be a JSON object"))),
+            Err(e) => return Some((400, error_json(&format!("Bad JSON: {}", e)))),
+        };
+        match handler(self, &body, stream) {
+            Ok(response) => response.map(|r| (200, r)),
+            Err(e) => Some((e.status, e.json())),
+        }
+    }
+
+    fn models(&self) -> Value {
+        json!({
+            "object": "list",
+            "data": [{ "id": self.model, "object": "model", "created": 0, "owned_by": "llama2-rs" }],
+        })
+    }
+
+    fn completions(
+        &self,
+        body: &Value,
+        stream: &mut TcpStream,
+    ) -> Result<Option<Value>, HttpError> {
+        let prompt = match &body["prompt"] {
+            Value::String(p) => self.encode_prompt(p),
+            Value::Array(ps) if ps.len() == 1 && ps[0].is_string() => {
+                self.encode_prompt(ps[0].as_str().unwrap())
+            }
+            Value::Array(ids) if ids.iter().all(Value::is_u64) => {
+                let ids = ids.iter().map(|id| id.as_u64().unwrap() as usize);
+                let mut prompt = vec![BOS];
+                prompt.extend(ids.skip_while(|&id| id == BOS));
+                if prompt.iter().any(|&id| id >= self.cfg.vocab_size) {
+                    return Err("Prompt token out of vocab".into());
+                }
+                prompt
+            }
+            Value::Null => vec![BOS],
+            _ => return Err("prompt must be a string or an array of token ids".into()),
+        };
+        let logprobs = opt_usize(body, "logprobs")?;
+        let req = self.request(body, prompt, 16, logprobs)?;
+        let events = self.submit(&req)?;
+        let id = format!("cmpl-{}", self.next_id.fetch_add(1, Ordering::Relaxed));
+        let created = unix_time();
+        let response = |text: &str, lps: Option<Value>, finish_reason: Value| {
+            json!({
+                "id": id,
+                "object": "text_completion",
+                "created": created,
+                "model": self.model,
+                "choices": [{
+                    "text": text,
+                    "index": 0,
+                    "logprobs": lps,
+                    "finish_reason": finish_reason,
+                }],
+            })
+        };
+
+        if body["stream"].as_bool() == Some(true) {
+            if start_events(stream).is_err() {
+                return Ok(None);
+            }
+            let mut offset = 0;
+            let out = self.generate(&req, events, |text, lps| {
+                let lps = logprobs.map(|_| self.completion_logprobs(lps, offset));
+                offset += text.len();
+                write_event(stream, &response(text, lps, Value::Null)).is_ok()
+            });
+            let last = out.map(|out| {
+                out.map(|out| {
+                    let mut last = response("", None, json!(out.finish_reason));
+                    if body["stream_options"]["include_usage"].as_bool() == Some(true) {
+                        last["usage"] = usage(&out);
+                    }
+                    last
+                })
+            });
+            end_stream(stream, last);
+            return Ok(None);
+        }
+
+        let out = self.generate(&req, events, |_, _| true)?.unwrap();
+        let lps = logprobs.map(|_| self.completion_logprobs(&out.logprobs, 0));
+        let mut response = response(&out.text, lps, json!(out.finish_reason));
+        response["usage"] = usage(&out);
+        Ok(Some(response))
+    }
+
+    fn chat_completions(
+        &self,
+        body: &Value,
+        stream: &mut TcpStream,
+    ) -> Result<Option<Value>, HttpError> {
+        let messages = body["messages"]
+            .as_array()
+            .ok_or("messages must be an array")?
+            .iter()
+            .map(parse_message)
+            .collect::<Result<Vec<_>, _>>()?;
+        let prompt = self.template.render(&self.vocab, &messages)?;
+        let logprobs = match body["logprobs"].as_bool() {
+            Some(true) => Some(opt_usize(body, "top_logprobs")?.unwrap_or(0)),
+            _ => None,
+        };
+        let max_tokens = opt_usize(body, "max_completion_tokens")?.unwrap_or(usize::MAX);
+        let mut req = self.request(body, prompt, max_tokens, logprobs)?;
+        req.stop.extend(self.template.stop.iter().cloned());
+        let events = self.submit(&req)?;
+        let id = format!("chatcmpl-{}", self.next_id.fetch_add(1, Ordering::Relaxed));
+        let created = unix_time();
+
+        if body["stream"].as_bool() == Some(true) {
+            let chunk = |delta: Value, lps: Option<Value>, finish_reason: Value| {
+                json!({
+                    "id": id,
+                    "object": "chat.completion.chunk",
+                    "created": created,
+                    "model": self.model,
+                    "choices": [{
+                        "index": 0,
+                        "delta": delta,
+                        "logprobs": lps,
+                        "finish_reason": finish_reason,
+                    }],
+                })
+            };
+            let first = chunk(
+                json!({ "role": "assistant", "content": "" }),
+                None,
+                Value::Null,
+            );
+            if start_events(stream)
+                .and_then(|_| write_event(stream, &first))
+                .is_err()
+            {
+                return Ok(None);
+            }
+            let mut started = false;
+            let out = self.generate(&req, events, |text, lps| {
+                // answers tend to start with a space or a new line
+                let text = if started { text } else { text.trim_start() };
+                started |= !text.is_empty();
+                let lps = logprobs.map(|_| self.chat_logprobs(lps));
+                let delta = json!({ "content": text });
+                write_event(stream, &chunk(delta, lps, Value::Null)).is_ok()
+            });
+            let last = out.map(|out| {
+                out.map(|out| {
+                    let mut last = chunk(json!({}), None, json!(out.finish_reason));
+                    if body["stream_options"]["include_usage"].as_bool() == Some(true) {
+                        last["usage"] = usage(&out);
+                    }
+                    last
+                })
+            });
+            end_stream(stream, last);
+            return Ok(None);
+        }
+
+        let out = self.generate(&req, events, |_, _| true)?.unwrap();
+        Ok(Some(json!({
+            "id": id,
+            "object": "chat.completion",
+            "created": created,
+            "model": self.model,
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": out.text.trim_start() },
+                "logprobs": logprobs.map(|_| self.chat_logprobs(&out.logprobs)),
+                "finish_reason": out.finish_reason,
+            }],
+            "usage": usage(&out),
+        })))
+    }
+
+    /// Completions `logprobs`, `offset` is where the first token starts in the text
+    fn completion_logprobs(&self, lps: &[TokenLogprobs], mut offset: usize) -> Value {
+        let mut text_offset = vec![];
+        for t in lps {
+            text_offset.push(offset);
+            offset += self.vocab.token_bytes(t.token).len();
+        }
+        let top = lps.iter().map(|t| {
+            t.top
+                .iter()
+                .map(|&(token, logprob)| (self.vocab.token_text(token).into(), json!(logprob)))
+                .collect::<serde_json::Map<_, _>>()
+        });
+        json!({
+            "tokens": lps.iter().map(|t| self.vocab.token_text(t.token)).collect::<Vec<_>>(),
+            "token_logprobs": lps.iter().map(|t| t.logprob).collect::<Vec<_>>(),
+            "top_logprobs": top.collect::<Vec<_>>(),
+            "text_offset": text_offset,
+        })
+    }
+
+    /// Chat completions `logprobs`
+    fn chat_logprobs(&self, lps: &[TokenLogprobs]) -> Value {
+        let entry = |token: usize, logprob: Ty| {
+            json!({
+                "token": self.vocab.token_text(token),
+                "logprob": logprob,
+                "bytes": self.vocab.token_bytes(token),
+            })
+        };
+        let content = lps.iter().map(|t| {
+            let mut e = entry(t.token, t.logprob);
+            e["top_logprobs"] = t.top.iter().map(|&(t, l)| entry(t, l)).collect();
+            e
+        });
+        json!({ "content": content.collect::<Vec<_>>() })
+    }
+
+    fn encode_prompt(&self, prompt: &str) -> Vec<usize> {
+        let mut tokens = vec![BOS];
+        if !prompt.is_empty() {
+            tokens.extend(self.vocab.encode(prompt));
+        }
+        tokens
+    }
+
+    /// The sampling fields shared by both endpoints
+    fn request(
+        &self,
+        body: &Value,
+        prompt: Vec<usize>,
+        default_max_tokens: usize,
+        logprobs: Option<usize>,
+    ) -> Result<Request, String> {
+        if prompt.len() >= self.cfg.seq_len {
+            return Err(format!(
+                "Prompt is {} tokens, the context is {}",
+                prompt.len(),
+                self.cfg.seq_len
+            ));
+        }
+        if opt_usize(body, "n")?.is_some_and(|n| n != 1) {
+            return Err("Only n = 1 is supported".into());
+        }
+        let params = SamplingParams::from_json(body)?;
+        let stop = match &body["stop"] {
+            Value::Null => vec![],
+            Value::String(s) => vec![s.clone()],
+            Value::Array(ss) => ss
+                .iter()
+                .map(|s| s.as_str().map(str::to_string))
+                .collect::<Option<Vec<_>>>()
+                .ok_or("stop must be a string or an array of strings")?,
+            _ => return Err("stop must be a string or an array of strings".into()),
+        };
+        Ok(Request {
+            prompt,
+            params,
+            max_tokens: opt_usize(body, "max_tokens")?.unwrap_or(default_max_tokens),
+            stop: stop.into_iter().filter(|s| !s.is_empty()).collect(),
+            logprobs,
+        })
+    }
+
+    fn submit(&self, req: &Request) -> Result<Events, String> {
+        self.scheduler.submit(SequenceRequest {
+            prompt: req.prompt.clone(),
+            pipeline: req.params.pipeline(),
+            max_tokens: req.max_tokens,
+            logprobs: req.logprobs,
+        })
+    }
+
+    /// Collect the completion from the scheduler `events`, `emit` gets the text as it becomes
+    /// final (complete UTF-8, no possible start of a stop string) with the log probabilities of
+    /// the tokens since the last call. Generation is cancelled, returning `None`, when `emit`
+    /// returns false. Fails when the scheduler could not finish the sequence.
+    fn generate(
+        &self,
+        req: &Request,
+        events: Events,
+        mut emit: impl FnMut(&str, &[TokenLogprobs]) -> bool,
+    ) -> Result<Option<Completion>, HttpError> {
+        let mut text = TextStream::new(req.stop.clone());
+        let mut logprobs = vec![];
+        let mut emitted = 0;
+        let mut completion_tokens = 0;
+        // returning drops `events`, which cancels the sequence
+        let finish_reason = loop {
+            let event = events
+                .recv()
+                .map_err(|_| HttpError::internal("Scheduler dropped the request"))?;
+            let (token, lps) = match event {
+                Event::Token(token, lps) => (token, lps),
+                Event::Done(FinishReason::Eos) => break "stop",
+                Event::Done(FinishReason::Length) => break "length",
+                Event::Error(e) => return Err(HttpError::unavailable(e)),
+            };
+            logprobs.extend(lps);
+            completion_tokens += 1;
+            let (chunk, stopped) = text.push(self.vocab.token_bytes(token));
+            if !chunk.is_empty() {
+                if !emit(&chunk, &logprobs[emitted..]) {
+                    return Ok(None);
+                }
+                emitted = logprobs.len();
+            }
+            if stopped {
+                break "stop";
+            }
+        };
+        let rest = text.flush();
+        if (!rest.is_empty() || emitted < logprobs.len()) && !emit(&rest, &logprobs[emitted..]) {
+            return Ok(None);
+        }
+        Ok(Some(Completion {
+            text: text.text(),
+            logprobs,
+            finish_reason,
+            prompt_tokens: req.prompt.len(),
+            completion_tokens,
+        }))
+    }
+}
+
+fn parse_message(message: &Value) -> Result<Message, String> {
+    let role = message["role"]
+        .as_str()
+        .ok_or("message role must be a string")?;
+    let content = match &message["content"] {
+        Value::String(c) => c.clone(),
+        // content parts, only text is supported
+        Value::Array(parts) => parts
+            .iter()
+            .map(|p| p["text"].as_str())
+            .collect::<Option<String>>()
+            .ok_or("Only text message content is supported")?,
+        _ => return Err("message content must be a string".into()),
+    };
+    Ok(Message::new(role, &content))
+}
+
+fn usage(out: &Completion) -> Value {
+    json!({
+        "prompt_tokens": out.prompt_tokens,
+        "completion_tokens": out.completion_tokens,
+        "total_tokens": out.prompt_tokens + out.completion_tokens,
+    })
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn error_json(message: &str) -> Value {
+    json!({ "error": { "message": message, "type": "invalid_request_error" } })
+}
+
+/// Method, path and body of the next request on `stream`
+fn read_request(stream: &mut TcpStream) -> Result<(String, String, Vec<u8>), String> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    let mut head = 0;
+    read_head_line(&mut reader, &mut line, &mut head)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err("Bad request line".into());
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let mut content_length = 0;
+    let mut expect_continue = false;
+    loop {
+        read_head_line(&mut reader, &mut line, &mut head)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return Err("Bad header".into());
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.parse().map_err(|_| "Bad Content-Length")?;
+        } else if name.eq_ignore_ascii_case("expect") {
+            expect_continue = value.eq_ignore_ascii_case("100-continue");
+        }
+    }
+    if content_length > MAX_BODY {
+        return Err(format!("Request body is larger than {} bytes", MAX_BODY));
+    }
+    if expect_continue {
+        let stream = reader.get_mut();
+        stream
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .map_err(|e| e.to_string())?;
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).map_err(|e| e.to_string())?;
+    Ok((method, path, body))
+}
+
+/// Read the next line of the request head into `line`, failing if it is longer than
+/// `MAX_HEAD_LINE` or makes the head (`head` bytes so far) larger than `MAX_HEAD`
+fn read_head_line(
+    reader: &mut impl BufRead,
+    line: &mut String,
+    head: &mut usize,
+) -> Result<(), String> {
+    line.clear();
+    let limit = MAX_HEAD_LINE.min(MAX_HEAD - *head);
+    let read = reader
+        .take(limit as u64)
+        .read_line(line)
+        .map_err(|e| e.to_string())?;
+    *head += read;
+    if read == limit && !line.ends_with('\n') {
+        return Err("Request head is too large".into());
+    }
+    Ok(())
+}
+
+/// Response head of an event stream, `Nagle` off so tokens go out as they come
+fn start_events(stream: &mut TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    stream.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+    )?;
+    stream.flush()
+}
+
+fn write_event(stream: &mut TcpStream, data: &Value) -> io::Result<()> {
+    write!(stream, "data: {}\n\n", data)?;
+    stream.flush()
+}
+
+fn end_events(stream: &mut TcpStream) -> io::Result<()> {
+    stream.write_all(b"data: [DONE]\n\n")?;
+    stream.flush()
+}
+
+/// Finish an event stream with its `last` event, or an error event when generation failed.
+/// Nothing is left to send once the client is gone (`Ok(None)`).
+fn end_stream(stream: &mut TcpStream, last: Result<Option<Value>, HttpError>) {
+    let last = match last {
+        Ok(Some(last)) => last,
+        Ok(None) => return,
+        Err(e) => e.json(),
+    };
+    let _ = write_event(stream, &last).and_then(|_| end_events(stream));
+}
+
+fn write_json(stream: &mut TcpStream, status: u16, body: &Value) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
@@ -0,0 +1,311 @@
+//! Sessions hold everything that belongs to a single sequence (activations and K, V block table),
+//! so one set of weights can serve many requests.
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::hooks::{ActivationHook, NoHook};
+use crate::kv::{blocks_for, SharedPool, BLOCK_SIZE};
+use crate::sampling::{log_softmax, top_logprobs, Pipeline, TokenLogprobs};
+use crate::{Config, ExecutionState, LamaExecuter, Ty};
+
+pub const POOL_EXHAUSTED: &str = "K, V block pool exhausted";
+
+pub struct Session {
+    pub state: ExecutionState<Vec<Ty>>,
+    pool: SharedPool,
+    /// Pool blocks holding this sequence K, V, in position order
+    table: Vec<usize>,
+    /// Tokens already in the K, V cache. Its length is the next position.
+    tokens: Vec<usize>,
+}
+
+impl Session {
+    pub fn new(cfg: &Config, pool: &SharedPool) -> Self {
+        Self {
+            state: ExecutionState::init(cfg),
+            pool: Arc::clone(pool),
+            table: vec![],
+            tokens: vec![],
+        }
+    }
+
+    /// Copy of this session that shares its K, V blocks, they are copied once either side writes
+    pub fn fork(&self) -> Self {
+        let mut pool = self.pool.lock().unwrap();
+        self.table.iter().for_each(|&block| pool.retain(block));
+        Self {
+            state: self.state.clone(),
+            pool: Arc::clone(&self.pool),
+            table: self.table.clone(),
+            tokens: self.tokens.clone(),
+        }
+    }
+
+    /// Position of the next token
+    pub fn pos(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Tokens fed so far (prompt and generated)
+    pub fn tokens(&self) -> &[usize] {
+        &self.tokens
+    }
+
+    /// Logits of the last fed token, with the token history (for logits processing)
+    pub fn logits_mut(&mut self) -> (&[usize], &mut [Ty]) {
+        (&self.tokens, &mut self.state.logits)
+    }
+
+    /// Run `token` through the model, its logits end up in `state.logits`.
+    /// Fails, leaving the session as it was, when the pool has no block for the token.
+    pub fn feed<W: LamaExecuter<Vec<Ty>>>(
+        &mut self,
+        weights: &W,
+        cfg: &Config,
+        token: usize,
+    ) -> Result<(), String> {
+        self.feed_hooked(weights, cfg, token, &mut NoHook)
+    }
+
+    /// [`feed`](Self::feed), handing intermediate tensors to `hook`
+    pub fn feed_hooked<W: LamaExecuter<Vec<Ty>>>(
+        &mut self,
+        weights: &W,
+        cfg: &Config,
+        token: usize,
+        hook: &mut dyn ActivationHook<Vec<Ty>>,
+    ) -> Result<(), String> {
+        let pos = self.pos();
+        assert!(
+            pos < cfg.seq_len,
+            "Position {} is past the context of {} tokens",
+            pos,
+            cfg.seq_len
+        );
+        // the pool is only locked to find the blocks, the forward pass runs without it
+        let mut kv = {
+            let mut pool = self.pool.lock().unwrap();
+            let idx = pos / BLOCK_SIZE;
+            if idx == self.table.len() {
+                let block = pool.alloc().ok_or(POOL_EXHAUSTED)?;
+                self.table.push(block);
+            } else if pool.refs(self.table[idx]) > 1 {
+                // copy on write, someone else still reads the shared block
+                let block = pool.alloc().ok_or(POOL_EXHAUSTED)?;
+                pool.copy_block(self.table[idx], block);
+                pool.release(self.table[idx]);
+                self.table[idx] = block;
+            }
+            pool.paged(&self.table)
+        };
+        weights.step_hooked(token, pos, cfg, &mut self.state, &mut kv, hook);
+        self.tokens.push(token);
+        Ok(())
+    }
+
+    /// Forget the tokens from position `len` on, the next token goes there.
+    /// `state.logits` are stale until then.
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.pos() {
+            return;
+        }
+        self.tokens.truncate(len);
+        let mut pool = self.pool.lock().unwrap();
+        self.table
+            .drain(blocks_for(len)..)
+            .for_each(|block| pool.release(block));
+    }
+
+    /// Sample and feed tokens until `pipeline` says stop (or the context is full).
+    /// `on_token` sees every sampled token, including the last one which is not fed.
+    pub fn generate<W: LamaExecuter<Vec<Ty>>>(
+        &mut self,
+        weights: &W,
+        cfg: &Config,
+        pipeline: &mut Pipeline,
+        mut on_token: impl FnMut(usize),
+    ) -> Result<(), String> {
+        loop {
+            let (history, logits) = self.logits_mut();
+            let next = pipeline.next_token(history, logits);
+            on_token(next);
+            if pipeline.should_stop(self.tokens(), next) || self.pos() >= cfg.seq_len {
+                return Ok(());
+            }
+            self.feed(weights, cfg, next)?;
+        }
+    }
+
+    /// Like [`Session::generate`], also returning the log probability of every sampled token
+    /// and the `top_n` most likely tokens at its position
+    pub fn generate_logprobs<W: LamaExecuter<Vec<Ty>>>(
+        &mut self,
+        weights: &W,
+        cfg: &Config,
+        pipeline: &mut Pipeline,
+        top_n: usize,
+        mut on_token: impl FnMut(&TokenLogprobs),
+    ) -> Result<Vec<TokenLogprobs>, String> {
+        let mut out = vec![];
+        loop {
+            // before the pipeline, processors change the logits in place
+            let logprobs = log_softmax(&self.state.logits);
+            let (history, logits) = self.logits_mut();
+            let next = pipeline.next_token(history, logits);
+            out.push(TokenLogprobs {
+                token: next,
+                logprob: logprobs[next],
+                top: top_logprobs(&logprobs, top_n),
+            });
+            on_token(out.last().unwrap());
+            if pipeline.should_stop(self.tokens(), next) || self.pos() >= cfg.seq_len {
+                return Ok(out);
+            }
+            self.feed(weights, cfg, next)?;
+        }
+    }
+
+    /// Append a full block of `tokens` whose K, V are already in the pool
+    fn push_block(&mut self, block: usize, tokens: &[usize]) {
+        debug_assert_eq!(self.pos() % BLOCK_SIZE, 0);
+        self.pool.lock().unwrap().retain(block);
+        self.table.push(block);
+        self.tokens.extend_from_slice(tokens);
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let mut pool = self.pool.lock().unwrap();
+        self.table.iter().for_each(|&block| pool.release(block));
+    }
+}
+
+struct CachedBlock {
+    /// Key of this block in `PrefixCache::index`
+    key: (Option<usize>, Vec<usize>),
+    /// Number of cached blocks continuing this one
+    children: usize,
+    last_used: u64,
+}
+
+/// Cache of full K, V blocks keyed by the token prefix they hold.
+/// A K, V row only depends on the tokens up to its position, so a block is identified by its
+/// tokens and the (cached) block before it. Requests starting with a cached chain of blocks share
+/// those blocks instead of running the model over them.
+pub struct PrefixCache {
+    /// (previous block, block tokens) -> block
+    index: HashMap<(Option<usize>, Vec<usize>), usize>,
+    /// block -> cache entry, the cache holds a reference on each of these
+    entries: HashMap<usize, CachedBlock>,
+    /// Max number of cached blocks
+    capacity: usize,
+    clock: u64,
+}
+
+impl PrefixCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            index: HashMap::new(),
+            entries: HashMap::new(),
+            capacity,
+            clock: 0,
+        }
+    }
+
+    /// Prefill an empty `session` with `prompt`, sharing the longest cached prefix
+    /// and running only the rest through the model. Returns the number of reused tokens.
+    ///
+    /// The last prompt token is always computed, we need its logits to sample the next token.
+    pub fn prefill<W: LamaExecuter<Vec<Ty>>>(
+        &mut self,
+        session: &mut Session,
+        weights: &W,
+        cfg: &Config,
+        prompt: &[usize],
+    ) -> Result<usize, String> {
+        let reused = self.share(session, prompt);
+        for &token in prompt[reused..].iter() {
+            session.feed(weights, cfg, token)?;
+        }
+        self.store(session, prompt);
+        Ok(reused)
+    }
+
+    /// First half of [`PrefixCache::prefill`]: put the longest cached prefix of `prompt` in an
+    /// empty `session` (leaving at least the last token to compute). Returns its length.
+    pub fn share(&mut self, session: &mut Session, prompt: &[usize]) -> usize {
+        assert_eq!(
+            session.pos(),
+            0,
+            "Prefix cache can only prefill an empty session"
+        );
+        self.clock += 1;
+
+        let shareable = prompt.len().saturating_sub(1) / BLOCK_SIZE;
+        let mut prev = None;
+        for chunk in prompt.chunks_exact(BLOCK_SIZE).take(shareable) {
+            let Some(&block) = self.index.get(&(prev, chunk.to_vec())) else {
+                break;
+            };
+            self.entries.get_mut(&block).unwrap().last_used = self.clock;
+            session.push_block(block, chunk);
+            prev = Some(block);
+        }
+        session.pos()
+    }
+
+    /// Second half of [`PrefixCache::prefill`]: cache the full blocks of `prompt`,
+    /// once `session` ran through all of it
+    pub fn store(&mut self, session: &Session, prompt: &[usize]) {
+        assert!(
+            session.tokens().starts_with(prompt),
+            "Session doesn't hold the prompt"
+        );
+        let mut prev = None;
+        for (idx, chunk) in prompt.chunks_exact(BLOCK_SIZE).enumerate() {
+            let key = (prev, chunk.to_vec());
+            let block = match self.index.get(&key) {
+                // shared, or computed twice: keep the cached copy
+                Some(&block) => block,
+                None => {
+                    let block = session.table[idx];
+                    session.pool.lock().unwrap().retain(block);
+                    if let Some(prev) = prev {
+                        self.entries.get_mut(&prev).unwrap().children += 1;
+                    }
+                    self.index.insert(key.clone(), block);
+                    let entry = CachedBlock {
+                        key,
+                        children: 0,
+                        last_used: self.clock,
+                    };
+                    self.entries.insert(block, entry);
+                    block
+                }
+            };
+            prev = Some(block);
+        }
+
+        self.evict(session);
+    }
+
+    /// Drop least recently used blocks (with no cached continuation) until we are within capacity
+    fn evict(&mut self, session: &Session) {
+        let mut pool = session.pool.lock().unwrap();
+        while self.entries.len() > self.capacity {
+            let (&block, _) = self
+                .entries
+                .iter()
+                .filter(|(_, e)| e.children == 0)
+                .min_by_key(|(_, e)| e.last_used)
+                .unwrap();
+            let entry = self.entries.remove(&block).unwrap();
+            if let Some(prev) = entry.key.0 {
+                self.entries.get_mut(&prev).unwrap().children -= 1;
+            }
+            self.index.remove(&entry.key);
+            pool.release(block);
+        }
+    }
+}
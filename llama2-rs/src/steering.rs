@@ -0,0 +1,213 @@
+//! Activation steering.
+//! A steering vector is a direction in the residual stream of a layer, e.g. "happy minus sad",
+//! computed as the mean activation difference over contrastive prompt pairs. [`Steered`] weights
+//! add the scaled vectors to the residual stream after their layers on every step.
+use std::collections::BTreeMap;
+
+use serde_json::{json, Value};
+
+use crate::hooks::{ActivationHook, Capture, Stage};
+use crate::kv::{PagedKV, SharedPool};
+use crate::patch::ModelPatch;
+use crate::session::{Session, POOL_EXHAUSTED};
+use crate::{Config, ExecutionState, LamaExecuter, Ty};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Steering {
+    /// (dim,) direction by layer
+    pub vectors: BTreeMap<usize, Vec<Ty>>,
+    /// Multiplies the vectors when injected
+    pub scale: Ty,
+}
+
+impl Steering {
+    /// Mean difference, over the `(positive, negative)` prompt pairs (tokens, BOS included), of
+    /// the residual stream at the last prompt token after each of `layers`
+    pub fn from_pairs<W: LamaExecuter<Vec<Ty>>>(
+        weights: &W,
+        cfg: &Config,
+        pool: &SharedPool,
+        pairs: &[(Vec<usize>, Vec<usize>)],
+        layers: &[usize],
+    ) -> Self {
+        assert!(!pairs.is_empty(), "Need at least one prompt pair");
+        let mut vectors: BTreeMap<usize, Vec<Ty>> =
+            layers.iter().map(|&l| (l, vec![0.; cfg.dim])).collect();
+        for (positive, negative) in pairs {
+            for (prompt, sign) in [(positive, 1.), (negative, -1.)] {
+                for (layer, x) in last_residuals(weights, cfg, pool, prompt, layers) {
+                    let v = vectors.get_mut(&layer).unwrap();
+                    v.iter_mut()
+                        .zip(x)
+                        .for_each(|(v, x)| *v += sign * x / pairs.len() as Ty);
+                }
+            }
+        }
+        Self { vectors, scale: 1. }
+    }
+
+    /// `{"vectors": {"<layer>": [...]}}`, the scale is chosen when loading
+    pub fn to_json(&self) -> Value {
+        let vectors = self
+            .vectors
+            .iter()
+            .map(|(l, v)| (l.to_string(), json!(v)))
+            .collect::<serde_json::Map<_, _>>();
+        json!({ "vectors": vectors })
+    }
+
+    /// Fails if a vector is on a layer `patch` skips, it would never be added
+    pub fn check_patch(&self, patch: &ModelPatch) -> Result<(), String> {
+        match self.vectors.keys().find(|l| patch.skip_layers.contains(l)) {
+            Some(layer) => Err(format!(
+                "Layer {} has a steering vector but is skipped",
+                layer
+            )),
+            None => Ok(()),
+        }
+    }
+
+    pub fn from_json(src: &str, cfg: &Config, scale: Ty) -> Result<Self, String> {
+        let json: Value = serde_json::from_str(src).map_err(|e| format!("Bad JSON: {}", e))?;
+        let entries = json["vectors"]
+            .as_object()
+            .ok_or("Steering file must have a vectors object")?;
+        let mut vectors = BTreeMap::new();
+        for (layer, v) in entries {
+            let layer = layer
+                .parse::<usize>()
+                .ok()
+                .filter(|&l| l < cfg.n_layers)
+                .ok_or_else(|| format!("Bad layer {}", layer))?;
+            let v: Vec<Ty> = v
+                .as_array()
+                .and_then(|v| v.iter().map(|x| x.as_f64().map(|x| x as Ty)).collect())
+                .ok_or_else(|| format!("Layer {} vector must be an array of numbers", layer))?;
+            if v.len() != cfg.dim {
+                return Err(format!(
+                    "Layer {} vector has {} values, the model dim is {}",
+                    layer,
+                    v.len(),
+                    cfg.dim
+                ));
+            }
+            vectors.insert(layer, v);
+        }
+        Ok(Self { vectors, scale })
+    }
+}
+
+/// Residual stream after `layers` at the last token of `prompt`
+fn last_residuals<W: LamaExecuter<Vec<Ty>>>(
+    weights: &W,
+    cfg: &Config,
+    pool: &SharedPool,
+    prompt: &[usize],
+    layers: &[usize],
+) -> Vec<(usize, Vec<Ty>)> {
+    let mut session = Session::new(cfg, pool);
+    let (last, context) = prompt.split_last().expect("Empty prompt");
+    context
+        .iter()
+        .for_each(|&t| session.feed(weights, cfg, t).expect(POOL_EXHAUSTED));
+    let mut capture = Capture::new(cfg, &[Stage::Residual], layers);
+    session
+        .feed_hooked(weights, cfg, *last, &mut capture)
+        .expect(POOL_EXHAUSTED);
+    capture
+        .activations
+        .into_iter()
+        .map(|a| (a.layer, a.tensor))
+        .collect()
+}
+
+/// Weights steered on every step
+pub struct Steered<W> {
+    pub weights: W,
+    pub steering: Steering,
+}
+
+/// Adds the steering before handing tensors on to the caller hook
+struct SteeringHook<'a> {
+    steering: &'a Steering,
+    inner: &'a mut dyn ActivationHook<Vec<Ty>>,
+}
+
+impl ActivationHook<Vec<Ty>> for SteeringHook<'_> {
+    fn on_activation(&mut self, layer: usize, pos: usize, stage: Stage, tensor: &mut Vec<Ty>) {
+        if stage == Stage::Residual {
+            if let Some(v) = self.steering.vectors.get(&layer) {
+                let scale = self.steering.scale;
+                tensor.iter_mut().zip(v).for_each(|(x, v)| *x += scale * v);
+            }
+        }
+        self.inner.on_activation(layer, pos, stage, tensor);
+    }
+}
+
+impl<W: LamaExecuter<Vec<Ty>>> LamaExecuter<Vec<Ty>> for Steered<W> {
+    fn step_hooked(
+        &self,
+        token: usize,
+        pos: usize,
+        cfg: &Config,
+        state: &mut ExecutionState<Vec<Ty>>,
+        kv: &mut PagedKV<Vec<Ty>>,
+        hook: &mut dyn ActivationHook<Vec<Ty>>,
+    ) {
+        let mut hook = SteeringHook {
+            steering: &self.steering,
+            inner: hook,
+        };
+        self.weights
+            .step_hooked(token, pos, cfg, state, kv, &mut hook);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::tests::random_model;
+    use crate::kv::{blocks_for, BlockPool, KVFormat};
+    use std::sync::{Arc, Mutex};
+
+    const CFG: Config = Config {
+        dim: 64,
+        hidden_dim: 172,
+        n_layers: 2,
+        n_heads: 4,
+        n_kv_heads: 4,
+        vocab_size: 256,
+        seq_len: 16,
+        shared_weights: false,
+    };
+
+    /// Logits after every token of a fixed prompt
+    fn logits<W: LamaExecuter<Vec<Ty>>>(weights: &W) -> Vec<Vec<Ty>> {
+        let pool = BlockPool::new(&CFG, blocks_for(CFG.seq_len), KVFormat::F32);
+        let mut session = Session::new(&CFG, &Arc::new(Mutex::new(pool)));
+        (0..CFG.seq_len)
+            .map(|pos| {
+                session
+                    .feed(weights, &CFG, (pos * 37) % CFG.vocab_size)
+                    .unwrap();
+                session.state.logits.clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn zero_vector_changes_nothing() {
+        let weights = random_model(&CFG);
+        let steered = |v: Ty| Steered {
+            weights: &weights,
+            steering: Steering {
+                vectors: (0..CFG.n_layers).map(|l| (l, vec![v; CFG.dim])).collect(),
+                scale: 4.,
+            },
+        };
+        let base = logits(&weights);
+        assert_eq!(logits(&steered(0.)), base);
+        assert_ne!(logits(&steered(0.1)), base);
+    }
+}
@@ -0,0 +1,115 @@
+//! Text of generated tokens.
+
+/// Generated bytes, released as text once it is final: complete UTF-8 that can't be the
+/// start of a stop string. Generation text ends before the first stop string.
+pub struct TextStream {
+    bytes: Vec<u8>,
+    /// Bytes released so far
+    sent: usize,
+    stop: Vec<String>,
+}
+
+impl TextStream {
+    /// Empty stop strings are ignored
+    pub fn new(mut stop: Vec<String>) -> Self {
+        stop.retain(|s| !s.is_empty());
+        Self {
+            bytes: vec![],
+            sent: 0,
+            stop,
+        }
+    }
+
+    /// Add a token, returns the newly final text and whether a stop string was hit
+    pub fn push(&mut self, token: &[u8]) -> (String, bool) {
+        self.bytes.extend_from_slice(token);
+        // held back bytes can't be part of a released stop string, search from there
+        if let Some(at) = find_stop(&self.bytes[self.sent..], &self.stop) {
+            self.bytes.truncate(self.sent + at);
+            return (self.flush(), true);
+        }
+        let pending = &self.bytes[self.sent..];
+        let mut end = pending.len() - incomplete_utf8(pending);
+        end -= partial_stop(&pending[..end], &self.stop);
+        let text = String::from_utf8_lossy(&pending[..end]).into_owned();
+        self.sent += end;
+        (text, false)
+    }
+
+    /// Release everything left
+    pub fn flush(&mut self) -> String {
+        let text = String::from_utf8_lossy(&self.bytes[self.sent..]).into_owned();
+        self.sent = self.bytes.len();
+        text
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.bytes).into_owned()
+    }
+}
+
+/// Length of a UTF-8 sequence cut short at the end of `bytes`
+fn incomplete_utf8(bytes: &[u8]) -> usize {
+    for back in 1..=bytes.len().min(3) {
+        let byte = bytes[bytes.len() - back];
+        // skip continuation bytes back to the lead byte
+        if byte & 0xc0 != 0x80 {
+            let len = match byte {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => 1,
+            };
+            return if len > back { back } else { 0 };
+        }
+    }
+    0
+}
+
+/// Length of the longest end of `text` that is the start of a stop string
+fn partial_stop(text: &[u8], stop: &[String]) -> usize {
+    stop.iter()
+        .filter_map(|s| {
+            (1..s.len().min(text.len() + 1))
+                .rev()
+                .find(|&len| text.ends_with(&s.as_bytes()[..len]))
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Start of the first stop string in `text`
+fn find_stop(text: &[u8], stop: &[String]) -> Option<usize> {
+    stop.iter()
+        .filter_map(|s| text.windows(s.len()).position(|w| w == s.as_bytes()))
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_back_split_chars() {
+        let mut text = TextStream::new(vec![]);
+        let crab = "🦀".as_bytes();
+        assert_eq!(text.push(b" a"), (" a".into(), false));
+        assert_eq!(text.push(&crab[..1]), ("".into(), false));
+        assert_eq!(text.push(&crab[1..3]), ("".into(), false));
+        assert_eq!(text.push(&crab[3..]), ("🦀".into(), false));
+        assert_eq!(text.text(), " a🦀");
+    }
+
+    #[test]
+    fn stops_across_tokens() {
+        let mut text = TextStream::new(vec!["\nUser:".into(), "".into()]);
+        assert_eq!(text.push(b"Hi"), ("Hi".into(), false));
+        assert_eq!(text.push(b"!\nUs"), ("!".into(), false));
+        // not the stop string after all, the held back text comes out
+        assert_eq!(text.push(b"a"), ("\nUsa".into(), false));
+        assert_eq!(text.push(b"\nU"), ("".into(), false));
+        assert_eq!(text.push(b"ser: more"), ("".into(), true));
+        assert_eq!(text.text(), "Hi!\nUsa");
+        assert_eq!(text.flush(), "");
+    }
+}